@@ -0,0 +1,121 @@
+use crate::effect::Effect;
+use crate::transition::UnknownName;
+use orgb::Selector;
+use palette::{IntoColor, LinSrgb, Oklab};
+use std::str::FromStr;
+
+/// An effect that is stacked over the layers below it.
+pub struct Layer {
+    pub effect: Box<dyn Effect>,
+    /// How much the layer covers the layers below it, from 0 to 1.
+    pub opacity: f32,
+    pub blend: BlendMode,
+    /// The LEDs that the layer is drawn on, among the LEDs of its group, or `None` for all of them.
+    pub mask: Option<Selector>,
+}
+
+/// How the colors of a layer are combined with the colors below it. Colors are combined as lights, in linear sRGB.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlendMode {
+    /// The layer covers the colors below.
+    #[default]
+    Normal,
+    /// The lights add up.
+    Add,
+    /// The layer filters the colors below, so that white leaves them as they are and black turns them off.
+    Multiply,
+    /// The opposite of multiply: black leaves the colors below as they are and white turns them to white.
+    Screen,
+    /// The brightest of the two, for each channel.
+    Max,
+}
+
+impl BlendMode {
+    /// Combine the color of a layer with the color below it, with the opacity of the layer.
+    pub fn blend(self, below: Oklab, layer: Oklab, opacity: f32) -> Oklab {
+        // Keep the colors that are out of the sRGB gamut, for the fades in Oklab
+        if self == BlendMode::Normal && opacity >= 1.0 {
+            return layer;
+        }
+        let below: LinSrgb = below.into_color();
+        let layer: LinSrgb = layer.into_color();
+        let (below, layer) = (below.into_components(), layer.into_components());
+        let channel = |below: f32, layer: f32| {
+            let (below, layer) = (below.clamp(0.0, 1.0), layer.clamp(0.0, 1.0));
+            let blended = match self {
+                BlendMode::Normal => layer,
+                BlendMode::Add => (below + layer).min(1.0),
+                BlendMode::Multiply => below * layer,
+                BlendMode::Screen => 1.0 - (1.0 - below) * (1.0 - layer),
+                BlendMode::Max => below.max(layer),
+            };
+            below + (blended - below) * opacity
+        };
+        LinSrgb::new(
+            channel(below.0, layer.0),
+            channel(below.1, layer.1),
+            channel(below.2, layer.2),
+        )
+        .into_color()
+    }
+}
+
+impl FromStr for BlendMode {
+    type Err = UnknownName;
+
+    fn from_str(s: &str) -> Result<BlendMode, UnknownName> {
+        match s {
+            "normal" => Ok(BlendMode::Normal),
+            "add" => Ok(BlendMode::Add),
+            "multiply" => Ok(BlendMode::Multiply),
+            "screen" => Ok(BlendMode::Screen),
+            "max" => Ok(BlendMode::Max),
+            _ => Err(UnknownName {
+                name: s.into(),
+                expected: &["normal", "add", "multiply", "screen", "max"],
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::{from_rgb, to_rgb};
+    use orgb::Rgb;
+
+    fn blend(mode: BlendMode, below: Rgb, layer: Rgb, opacity: f32) -> Rgb {
+        to_rgb(mode.blend(from_rgb(below), from_rgb(layer), opacity))
+    }
+
+    #[test]
+    fn blend_modes_combine_the_channels() {
+        let (red, blue) = (Rgb(200, 0, 0), Rgb(0, 0, 200));
+        let half = Rgb(128, 128, 128);
+        assert_eq!(blend(BlendMode::Normal, red, blue, 1.0), blue);
+        assert_eq!(blend(BlendMode::Add, red, blue, 1.0), Rgb(200, 0, 200));
+        assert_eq!(blend(BlendMode::Add, red, red, 1.0), Rgb(255, 0, 0));
+        assert_eq!(
+            blend(BlendMode::Max, red, Rgb(100, 0, 50), 1.0),
+            Rgb(200, 0, 50)
+        );
+        assert_eq!(blend(BlendMode::Screen, red, Rgb(0, 0, 0), 1.0), red);
+
+        // Multiplying by gray dims the colors below
+        let Rgb(r, g, b) = blend(BlendMode::Multiply, red, half, 1.0);
+        assert!(r.abs_diff(100) <= 1 && g == 0 && b == 0);
+        let Rgb(r, g, b) = blend(BlendMode::Screen, half, half, 1.0);
+        assert!(r.abs_diff(192) <= 1 && r == g && g == b);
+
+        // The opacity lets the colors below show through
+        assert_eq!(blend(BlendMode::Normal, red, blue, 0.0), red);
+        let Rgb(r, g, b) = blend(BlendMode::Normal, red, blue, 0.5);
+        assert!(r.abs_diff(100) <= 1 && g == 0 && b.abs_diff(100) <= 1);
+    }
+
+    #[test]
+    fn parse_blend_modes() {
+        assert_eq!("screen".parse(), Ok(BlendMode::Screen));
+        assert!("overlay".parse::<BlendMode>().is_err());
+    }
+}
@@ -0,0 +1,616 @@
+use crate::compositor::{BlendMode, Layer};
+use crate::effect::{Effect, EffectRegistry, Params};
+use crate::target::from_rgb;
+use crate::transition::{Fade, UnknownName};
+use crate::trigger::{Trigger, TriggerError};
+use orgb::{Rgb, Selector};
+use palette::{IntoColor, Oklab, Oklch};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use toml::Spanned;
+
+/// The configuration that is used when there is no configuration file.
+pub const DEFAULT_CONFIG: &str = include_str!("../default-config.toml");
+
+/// A lighting scheme, loaded from a configuration file.
+pub struct Config {
+    pub groups: Vec<Group>,
+    pub states: Vec<State>,
+    /// Index of the state that the lighting starts in.
+    pub initial_state: usize,
+    /// Transitions between the states, by order of priority.
+    pub transitions: Vec<Transition>,
+}
+
+/// A group of devices.
+pub struct Group {
+    pub name: String,
+    pub selector: Selector,
+}
+
+/// What the groups of devices do in a state.
+pub struct State {
+    pub name: String,
+    /// OpenRGB profile to load when entering the state.
+    pub profile: Option<String>,
+    /// The layers of each group, in the order of the groups, from the bottom to the top. Devices are left alone in the
+    /// states without a layer.
+    pub layers: Vec<Vec<Layer>>,
+}
+
+/// A change of state, which happens when its trigger fires.
+pub struct Transition {
+    /// Indices of the states that the transition leaves from, or `None` to leave from any state.
+    pub from: Option<Vec<usize>>,
+    pub to: usize,
+    pub trigger: Trigger,
+    /// How the previous state fades into the new one.
+    pub fade: Fade,
+}
+
+/// An error in a configuration file.
+#[derive(Debug)]
+pub struct ConfigError {
+    /// The line of the error, starting from 1, if the error is about a specific part of the file.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Layout of the file, before validation
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    colors: BTreeMap<String, Spanned<String>>,
+    #[serde(default)]
+    devices: BTreeMap<String, Spanned<String>>,
+    initial_state: Option<Spanned<String>>,
+    #[serde(default)]
+    states: BTreeMap<String, RawState>,
+    #[serde(default)]
+    transitions: Vec<RawTransition>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawState {
+    profile: Option<String>,
+    #[serde(default)]
+    effects: BTreeMap<Spanned<String>, RawEffect>,
+    #[serde(default)]
+    layers: BTreeMap<Spanned<String>, Vec<RawEffect>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEffect {
+    effect: Spanned<String>,
+    #[serde(default)]
+    colors: Vec<Spanned<String>>,
+    period: Option<Spanned<f32>>,
+    opacity: Option<Spanned<f32>>,
+    blend: Option<Spanned<String>>,
+    mask: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTransition {
+    from: Option<Spanned<OneOrMany>>,
+    to: Spanned<String>,
+    on: Spanned<String>,
+    duration: Option<Spanned<f32>>,
+    easing: Option<Spanned<String>>,
+    blend: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Config {
+    /// Read and validate a configuration file.
+    pub fn load(path: impl AsRef<Path>, effects: &EffectRegistry) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| ConfigError {
+            line: None,
+            message: format!("could not read {}: {e}", path.display()),
+        })?;
+        Config::parse(&text, effects)
+    }
+
+    /// Validate the text of a configuration file.
+    pub fn parse(text: &str, effects: &EffectRegistry) -> Result<Config, ConfigError> {
+        let error = |span: Option<Range<usize>>, message: String| ConfigError {
+            line: span.map(|s| text[..s.start].matches('\n').count() + 1),
+            message,
+        };
+        let raw: RawConfig =
+            toml::from_str(text).map_err(|e| error(e.span(), e.message().into()))?;
+
+        let mut colors = BTreeMap::new();
+        for (name, color) in &raw.colors {
+            let value = parse_color(color.get_ref(), &BTreeMap::new())
+                .map_err(|e| error(Some(color.span()), e))?;
+            colors.insert(name.as_str(), value);
+        }
+
+        let mut groups = Vec::new();
+        for (name, selector) in &raw.devices {
+            let selector = selector
+                .get_ref()
+                .parse()
+                .map_err(|e| error(Some(selector.span()), format!("invalid selector: {e}")))?;
+            groups.push(Group {
+                name: name.clone(),
+                selector,
+            });
+        }
+
+        let mut states = Vec::new();
+        for (name, state) in &raw.states {
+            let mut state_layers: Vec<Vec<Layer>> = groups.iter().map(|_| Vec::new()).collect();
+            // The effect of a group is its bottom layer
+            let layers = state.effects.iter().chain(
+                state
+                    .layers
+                    .iter()
+                    .flat_map(|(group, specs)| specs.iter().map(move |spec| (group, spec))),
+            );
+            for (group, spec) in layers {
+                let group_idx = groups
+                    .iter()
+                    .position(|g| g.name == *group.get_ref())
+                    .ok_or_else(|| {
+                        error(
+                            Some(group.span()),
+                            format!("unknown device group {:?}", group.get_ref()),
+                        )
+                    })?;
+                let layer = build_layer(spec, &colors, effects)
+                    .map_err(|(span, e)| error(Some(span), e))?;
+                state_layers[group_idx].push(layer);
+            }
+            states.push(State {
+                name: name.clone(),
+                profile: state.profile.clone(),
+                layers: state_layers,
+            });
+        }
+
+        // States are referred to by name in the file
+        let state_idx = |name: &str, span: Range<usize>| {
+            states
+                .iter()
+                .position(|s| s.name == name)
+                .ok_or_else(|| error(Some(span), format!("unknown state {name:?}")))
+        };
+
+        let initial_state = match &raw.initial_state {
+            Some(name) => state_idx(name.get_ref(), name.span())?,
+            None => states
+                .iter()
+                .position(|s| s.name == "normal")
+                .ok_or_else(|| error(None, "missing initial_state".into()))?,
+        };
+
+        let mut transitions = Vec::new();
+        for transition in &raw.transitions {
+            let from = match &transition.from {
+                None => None,
+                Some(from) => {
+                    let names = match from.get_ref() {
+                        OneOrMany::One(name) => std::slice::from_ref(name),
+                        OneOrMany::Many(names) => &names[..],
+                    };
+                    let from = names.iter().map(|name| state_idx(name, from.span()));
+                    Some(from.collect::<Result<Vec<_>, _>>()?)
+                }
+            };
+            let to = state_idx(transition.to.get_ref(), transition.to.span())?;
+            let trigger = transition
+                .on
+                .get_ref()
+                .parse()
+                .map_err(|e: TriggerError| error(Some(transition.on.span()), e.to_string()))?;
+            let mut fade = Fade::default();
+            if let Some(duration) = &transition.duration {
+                let seconds = *duration.get_ref();
+                if !seconds.is_finite() || seconds < 0.0 {
+                    return Err(error(
+                        Some(duration.span()),
+                        "the duration must not be negative".into(),
+                    ));
+                }
+                fade.duration = seconds;
+            }
+            if let Some(easing) = &transition.easing {
+                fade.easing = easing
+                    .get_ref()
+                    .parse()
+                    .map_err(|e: UnknownName| error(Some(easing.span()), format!("easing: {e}")))?;
+            }
+            if let Some(blend) = &transition.blend {
+                fade.space = blend.get_ref().parse().map_err(|e: UnknownName| {
+                    error(Some(blend.span()), format!("blend space: {e}"))
+                })?;
+            }
+            transitions.push(Transition {
+                from,
+                to,
+                trigger,
+                fade,
+            });
+        }
+
+        Ok(Config {
+            groups,
+            states,
+            initial_state,
+            transitions,
+        })
+    }
+}
+
+/// Watches a configuration file, to reload it when it changes.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    // Modification time and length of the file when it was last loaded, or `None` if it did not exist
+    version: Option<(SystemTime, u64)>,
+}
+
+impl ConfigWatcher {
+    /// Start watching the file, which may not exist yet.
+    pub fn new(path: impl Into<PathBuf>) -> ConfigWatcher {
+        let path = path.into();
+        let version = file_version(&path);
+        ConfigWatcher { path, version }
+    }
+
+    /// Returns the new configuration if the file has been modified since the last call. A file that is removed is
+    /// not a change, so that the last configuration stays in use.
+    pub fn poll(&mut self, effects: &EffectRegistry) -> Option<Result<Config, ConfigError>> {
+        let version = file_version(&self.path);
+        if version.is_none() || version == self.version {
+            return None;
+        }
+        self.version = version;
+        Some(Config::load(&self.path, effects))
+    }
+}
+
+fn file_version(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn build_layer(
+    spec: &RawEffect,
+    colors: &BTreeMap<&str, Oklab>,
+    effects: &EffectRegistry,
+) -> Result<Layer, (Range<usize>, String)> {
+    let mut layer = Layer {
+        effect: build_effect(spec, colors, effects)?,
+        opacity: 1.0,
+        blend: BlendMode::Normal,
+        mask: None,
+    };
+    if let Some(opacity) = &spec.opacity {
+        if !(0.0..=1.0).contains(opacity.get_ref()) {
+            return Err((opacity.span(), "the opacity must be between 0 and 1".into()));
+        }
+        layer.opacity = *opacity.get_ref();
+    }
+    if let Some(blend) = &spec.blend {
+        layer.blend = blend
+            .get_ref()
+            .parse()
+            .map_err(|e: UnknownName| (blend.span(), format!("blend mode: {e}")))?;
+    }
+    if let Some(mask) = &spec.mask {
+        let selector = mask
+            .get_ref()
+            .parse()
+            .map_err(|e| (mask.span(), format!("invalid mask: {e}")))?;
+        layer.mask = Some(selector);
+    }
+    Ok(layer)
+}
+
+fn build_effect(
+    spec: &RawEffect,
+    colors: &BTreeMap<&str, Oklab>,
+    effects: &EffectRegistry,
+) -> Result<Box<dyn Effect>, (Range<usize>, String)> {
+    let mut params = Params::default();
+    for color in &spec.colors {
+        params
+            .colors
+            .push(parse_color(color.get_ref(), colors).map_err(|e| (color.span(), e))?);
+    }
+    if let Some(period) = &spec.period {
+        let seconds = *period.get_ref();
+        if !seconds.is_finite() || seconds <= 0.0 {
+            return Err((period.span(), "the period must be positive".into()));
+        }
+        params.period = Some(seconds);
+    }
+    effects
+        .create(spec.effect.get_ref(), &params)
+        .ok_or_else(|| {
+            let names: Vec<_> = effects.names().collect();
+            let message = format!(
+                "unknown effect {:?}, the effects are {names:?}",
+                spec.effect.get_ref()
+            );
+            (spec.effect.span(), message)
+        })
+}
+
+/// Parse a color written as `#rrggbb`, `srgb(r, g, b)`, `oklab(l, a, b)`, `oklch(l, c, h)` or as one of the named
+/// colors.
+fn parse_color(text: &str, named: &BTreeMap<&str, Oklab>) -> Result<Oklab, String> {
+    let text = text.trim();
+    let invalid = || format!("invalid color {text:?}");
+    if let Some(color) = named.get(text) {
+        return Ok(*color);
+    }
+    if let Some(hex) = text.strip_prefix('#') {
+        // Parsing alone would accept a sign
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let value = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+        let [_, r, g, b] = value.to_be_bytes();
+        return Ok(from_rgb(Rgb(r, g, b)));
+    }
+
+    let (function, args) = text
+        .strip_suffix(')')
+        .and_then(|t| t.split_once('('))
+        .ok_or_else(invalid)?;
+    let args = args
+        .split(',')
+        .map(|a| a.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    let [x, y, z] = args[..] else {
+        return Err(format!("expected 3 components in color {text:?}"));
+    };
+    // Parsing accepts NaN and infinities, which would spread through every blend
+    if !args.iter().all(|c| c.is_finite()) {
+        return Err(format!(
+            "components must be finite numbers in color {text:?}"
+        ));
+    }
+    match function.trim() {
+        "srgb" => {
+            if ![x, y, z].iter().all(|c| (0.0..=255.0).contains(c)) {
+                return Err(format!(
+                    "srgb components must be between 0 and 255 in {text:?}"
+                ));
+            }
+            let [r, g, b] = [x, y, z].map(|c| c.round() as u8);
+            Ok(from_rgb(Rgb(r, g, b)))
+        }
+        "oklab" => Ok(Oklab::new(x, y, z)),
+        "oklch" => Ok(Oklch::new(x, y, z).into_color()),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transition::BlendSpace;
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        Config::parse(text, &EffectRegistry::new())
+    }
+
+    fn error_line(text: &str) -> Option<usize> {
+        parse(text).err().expect("the config is valid").line
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        let config = parse(DEFAULT_CONFIG).unwrap();
+        assert_eq!(config.groups.len(), 1);
+        assert_eq!(config.states.len(), 2);
+        assert!(config.states.iter().all(|s| s.layers[0].len() == 1));
+        assert_eq!(config.states[config.initial_state].name, "normal");
+        assert_eq!(config.transitions.len(), 3);
+    }
+
+    #[test]
+    fn parse_colors() {
+        let named = BTreeMap::from([("orange", Oklab::new(0.5, 0.24, 0.29))]);
+        assert_eq!(parse_color("orange", &named), Ok(named["orange"]));
+        assert_eq!(
+            parse_color("oklab(0.5, 0.1, -0.1)", &named),
+            Ok(Oklab::new(0.5, 0.1, -0.1))
+        );
+        assert_eq!(
+            parse_color("#ff8000", &named),
+            parse_color("srgb(255, 128, 0)", &named)
+        );
+        assert_eq!(
+            parse_color("srgb(254.6, 127.5, 0.4)", &named),
+            parse_color("#ff8000", &named)
+        );
+        let white = parse_color("#ffffff", &named).unwrap();
+        assert!((white.l - 1.0).abs() < 1e-3);
+        let gray: Oklab = parse_color("oklch(0.5, 0, 120)", &named).unwrap();
+        assert!(gray.a.abs() < 1e-6 && gray.b.abs() < 1e-6);
+        for invalid in [
+            "#ff80",
+            "#gg8000",
+            "#+ff800",
+            "oklab(nan, 0, 0)",
+            "oklch(inf, 0, 0)",
+            "srgb(256, 0, 0)",
+            "oklab(0.5, 0.1)",
+            "hsl(1, 2, 3)",
+            "purple",
+        ] {
+            assert!(parse_color(invalid, &named).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn reload_when_the_file_changes() {
+        let path = std::env::temp_dir().join(format!("my-rgb-loop-{}.toml", std::process::id()));
+        let write = |text: &str, age: u64| {
+            std::fs::write(&path, text).unwrap();
+            // Modification times are not precise enough to tell apart two writes in a row
+            let modified = SystemTime::now() - std::time::Duration::from_secs(age);
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        let effects = EffectRegistry::new();
+        let _ = std::fs::remove_file(&path);
+        let mut watcher = ConfigWatcher::new(&path);
+        assert!(watcher.poll(&effects).is_none());
+
+        write(DEFAULT_CONFIG, 30);
+        assert!(watcher.poll(&effects).unwrap().is_ok());
+        assert!(watcher.poll(&effects).is_none());
+
+        write("[devices]\ndram = 'type=fridge'\n", 20);
+        assert_eq!(watcher.poll(&effects).unwrap().err().unwrap().line, Some(2));
+        assert!(watcher.poll(&effects).is_none());
+
+        write(DEFAULT_CONFIG, 10);
+        assert!(watcher.poll(&effects).unwrap().is_ok());
+        std::fs::remove_file(&path).unwrap();
+        assert!(watcher.poll(&effects).is_none());
+    }
+
+    #[test]
+    fn report_the_line_of_errors() {
+        let header = "[devices]\ndram = \"type=dram\"\n";
+        assert_eq!(error_line("[devices]\ndram = \"type=fridge\"\n"), Some(2));
+        assert_eq!(
+            error_line(&format!(
+                "{header}[states.normal]\neffects.fans = {{ effect = \"solid\" }}\n"
+            )),
+            Some(4)
+        );
+        assert_eq!(
+            error_line(&format!(
+                "{header}[states.sleep.effects]\n\ndram = {{ effect = \"disco\" }}\n"
+            )),
+            Some(5)
+        );
+        assert_eq!(
+            error_line(&format!(
+                "{header}[states.normal]\neffects.dram = {{ effect = \"solid\", colors = [\"#12\"] }}\n"
+            )),
+            Some(4)
+        );
+        assert_eq!(
+            error_line(&format!(
+                "{header}[states.normal]\neffects.dram = {{ effect = \"wave\", period = 0 }}\n"
+            )),
+            Some(4)
+        );
+        assert_eq!(
+            error_line(&format!(
+                "{header}[states.normal]\nlayers.dram = [\n{{ effect = \"solid\", opacity = 2 }},\n]\n"
+            )),
+            Some(5)
+        );
+        assert_eq!(
+            error_line(&format!(
+                "{header}[states.normal]\nlayers.dram = [{{ effect = \"solid\", blend = \"overlay\" }}]\n"
+            )),
+            Some(4)
+        );
+        assert_eq!(
+            error_line(&format!(
+                "{header}[states.normal]\nlayers.dram = [{{ effect = \"solid\", mask = \"led\" }}]\n"
+            )),
+            Some(4)
+        );
+        assert_eq!(error_line(&format!("{header}[schemes.normal]\n")), Some(3));
+        assert_eq!(error_line("[devices\n"), Some(1));
+        let error = parse("[devices]\n\ndram = 'name=\"Unterminated'\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "line 3: invalid selector: missing closing quote at offset 5"
+        );
+    }
+
+    #[test]
+    fn check_the_states_of_transitions() {
+        let states = "[states.normal]\n[states.sleep]\n";
+        let config = parse(&format!(
+            "{states}[[transitions]]\nfrom = ['normal', 'sleep']\nto = 'sleep'\non = 'idle 300'\n\
+             [[transitions]]\nto = 'normal'\non = 'signal wake'\nduration = 2\n"
+        ))
+        .unwrap();
+        assert_eq!(config.transitions[0].from, Some(vec![0, 1]));
+        assert_eq!(config.transitions[1].from, None);
+        assert_eq!(config.transitions[1].fade.duration, 2.0);
+
+        assert_eq!(
+            error_line("initial_state = 'party'\n[states.normal]\n"),
+            Some(1)
+        );
+        assert_eq!(parse("[states.sleep]\n").err().unwrap().line, None);
+        assert_eq!(
+            error_line(&format!(
+                "{states}[[transitions]]\nfrom = 'party'\nto = 'sleep'\non = 'lock'\n"
+            )),
+            Some(4)
+        );
+        assert_eq!(
+            error_line(&format!(
+                "{states}[[transitions]]\nto = 'sleep'\non = 'nap'\n"
+            )),
+            Some(5)
+        );
+        assert_eq!(
+            error_line(&format!(
+                "{states}[[transitions]]\nto = 'sleep'\non = 'lock'\nduration = -1\n"
+            )),
+            Some(6)
+        );
+        assert_eq!(
+            error_line(&format!(
+                "{states}[[transitions]]\nto = 'sleep'\non = 'lock'\n\neasing = 'bounce'\n"
+            )),
+            Some(7)
+        );
+        let config = parse(&format!(
+            "{states}[[transitions]]\nto = 'sleep'\non = 'lock'\nblend = 'oklch'\n"
+        ))
+        .unwrap();
+        assert_eq!(config.transitions[0].fade.space, BlendSpace::Oklch);
+    }
+}
@@ -0,0 +1,389 @@
+use palette::{IntoColor, Oklab, Oklch};
+use std::collections::BTreeMap;
+use std::f32::consts::TAU;
+
+// Color picker: https://observablehq.com/@shan/oklab-color-wheel
+
+/// A lighting effect, which gives the colors of a zone at any time.
+pub trait Effect: Send {
+    /// Render the colors of a zone of `leds` LEDs, `time` seconds after the effect started.
+    fn render(&self, time: f64, leds: usize) -> Vec<Oklab>;
+}
+
+/// Settings of an effect. Effects pick the colors they need in order, and fall back to their own defaults for the
+/// missing colors and for the period.
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    pub colors: Vec<Oklab>,
+    /// Duration of one cycle of the animation, in seconds.
+    pub period: Option<f32>,
+}
+
+impl Params {
+    fn color(&self, i: usize, default: Oklab) -> Oklab {
+        self.colors.get(i).copied().unwrap_or(default)
+    }
+
+    fn period(&self, default: f32) -> f32 {
+        self.period.unwrap_or(default)
+    }
+}
+
+/// Creates an effect from its settings.
+pub type EffectFactory = fn(&Params) -> Box<dyn Effect>;
+
+/// The effects that can be picked by name.
+pub struct EffectRegistry {
+    factories: BTreeMap<String, EffectFactory>,
+}
+
+impl EffectRegistry {
+    /// Create a registry that knows about the built-in effects.
+    pub fn new() -> EffectRegistry {
+        let mut registry = EffectRegistry {
+            factories: BTreeMap::new(),
+        };
+        registry.register("solid", |p| Box::new(Solid::new(p)));
+        registry.register("breathing", |p| Box::new(Breathing::new(p)));
+        registry.register("wave", |p| Box::new(Wave::new(p)));
+        registry.register("rainbow", |p| Box::new(Rainbow::new(p)));
+        registry.register("gradient", |p| Box::new(Gradient::new(p)));
+        registry.register("sparkle", |p| Box::new(Sparkle::new(p)));
+        registry.register("chase", |p| Box::new(Chase::new(p)));
+        registry.register("oklab_wave", |p| Box::new(OklabWave::new(p)));
+        registry
+    }
+
+    /// Add an effect, or replace the effect that has the same name.
+    pub fn register(&mut self, name: &str, factory: EffectFactory) {
+        self.factories.insert(name.into(), factory);
+    }
+
+    /// Create the effect with this name, or return `None` if there is no such effect.
+    pub fn create(&self, name: &str, params: &Params) -> Option<Box<dyn Effect>> {
+        self.factories.get(name).map(|factory| factory(params))
+    }
+
+    /// The names of the effects, in alphabetical order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+}
+
+const WHITE: Oklab = Oklab::new(1.0, 0.0, 0.0);
+const BLACK: Oklab = Oklab::new(0.0, 0.0, 0.0);
+
+/// Returns where an animation of this period is in its cycle, between 0 and 1.
+fn cycle(time: f64, period: f32) -> f32 {
+    // The time is only reduced to single precision once it is within the cycle, since it grows for as long as the
+    // program runs
+    (time / f64::from(period)).rem_euclid(1.0) as f32
+}
+
+/// All the LEDs have the same color.
+pub struct Solid {
+    color: Oklab,
+}
+
+impl Solid {
+    pub fn new(params: &Params) -> Solid {
+        Solid {
+            color: params.color(0, WHITE),
+        }
+    }
+}
+
+impl Effect for Solid {
+    fn render(&self, _time: f64, leds: usize) -> Vec<Oklab> {
+        vec![self.color; leds]
+    }
+}
+
+/// All the LEDs fade in and out together.
+pub struct Breathing {
+    color: Oklab,
+    period: f32,
+}
+
+impl Breathing {
+    pub fn new(params: &Params) -> Breathing {
+        Breathing {
+            color: params.color(0, WHITE),
+            period: params.period(4.0),
+        }
+    }
+}
+
+impl Effect for Breathing {
+    fn render(&self, time: f64, leds: usize) -> Vec<Oklab> {
+        let t = 0.5 - (cycle(time, self.period) * TAU).cos() * 0.5;
+        vec![self.color * t; leds]
+    }
+}
+
+/// A wave of light that travels along the zone.
+pub struct Wave {
+    color: Oklab,
+    background: Oklab,
+    period: f32,
+}
+
+impl Wave {
+    pub fn new(params: &Params) -> Wave {
+        Wave {
+            color: params.color(0, WHITE),
+            background: params.color(1, BLACK),
+            period: params.period(3.0),
+        }
+    }
+}
+
+impl Effect for Wave {
+    fn render(&self, time: f64, leds: usize) -> Vec<Oklab> {
+        let time_phase = cycle(time, self.period) * TAU;
+        (0..leds)
+            .map(|i| {
+                let space_phase = i as f32 / leds as f32 * TAU;
+                let t = (space_phase - time_phase).cos() * 0.5 + 0.5;
+                self.color * t + self.background * (1.0 - t)
+            })
+            .collect()
+    }
+}
+
+/// Every hue, spread along the zone and turning with time.
+pub struct Rainbow {
+    period: f32,
+}
+
+impl Rainbow {
+    pub fn new(params: &Params) -> Rainbow {
+        Rainbow {
+            period: params.period(10.0),
+        }
+    }
+}
+
+impl Effect for Rainbow {
+    fn render(&self, time: f64, leds: usize) -> Vec<Oklab> {
+        let time_phase = cycle(time, self.period);
+        (0..leds)
+            .map(|i| {
+                let hue = (time_phase + i as f32 / leds as f32) * 360.0;
+                Oklch::new(0.75, 0.15, hue).into_color()
+            })
+            .collect()
+    }
+}
+
+/// A still gradient that goes through all the colors, from the first LED to the last.
+pub struct Gradient {
+    colors: Vec<Oklab>,
+}
+
+impl Gradient {
+    pub fn new(params: &Params) -> Gradient {
+        let colors = match params.colors.len() {
+            0 => vec![
+                Oklab::new(0.9, -0.304, 0.151),
+                Oklab::new(0.9, 0.094, 0.327),
+            ],
+            1 => vec![params.colors[0]; 2],
+            _ => params.colors.clone(),
+        };
+        Gradient { colors }
+    }
+}
+
+impl Effect for Gradient {
+    fn render(&self, _time: f64, leds: usize) -> Vec<Oklab> {
+        let segments = (self.colors.len() - 1) as f32;
+        (0..leds)
+            .map(|i| {
+                let x = i as f32 / (leds.max(2) - 1) as f32 * segments;
+                let segment = (x as usize).min(self.colors.len() - 2);
+                let t = x - segment as f32;
+                self.colors[segment] * (1.0 - t) + self.colors[segment + 1] * t
+            })
+            .collect()
+    }
+}
+
+/// LEDs light up at random and fade out.
+pub struct Sparkle {
+    color: Oklab,
+    background: Oklab,
+    period: f32,
+}
+
+impl Sparkle {
+    /// Proportion of the LEDs that sparkle during each period.
+    const DENSITY: f32 = 0.2;
+
+    pub fn new(params: &Params) -> Sparkle {
+        Sparkle {
+            color: params.color(0, WHITE),
+            background: params.color(1, BLACK),
+            period: params.period(1.0),
+        }
+    }
+}
+
+impl Effect for Sparkle {
+    fn render(&self, time: f64, leds: usize) -> Vec<Oklab> {
+        // The same LEDs sparkle for the whole period, so that the frames do not depend on the frame rate
+        let step = (time / f64::from(self.period)).floor() as i64 as u32;
+        let fade = 1.0 - cycle(time, self.period);
+        (0..leds)
+            .map(|i| {
+                let t = if random(i as u32, step) < Sparkle::DENSITY {
+                    fade
+                } else {
+                    0.0
+                };
+                self.color * t + self.background * (1.0 - t)
+            })
+            .collect()
+    }
+}
+
+/// A pseudo-random number between 0 and 1, which is always the same for the same inputs.
+fn random(a: u32, b: u32) -> f32 {
+    let mut x = a.wrapping_mul(0x9e37_79b9) ^ b.wrapping_mul(0x85eb_ca6b);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    (x >> 8) as f32 / (1 << 24) as f32
+}
+
+/// A light that runs along the zone, followed by a fading tail.
+pub struct Chase {
+    color: Oklab,
+    background: Oklab,
+    period: f32,
+}
+
+impl Chase {
+    /// Length of the tail, as a proportion of the zone.
+    const TAIL: f32 = 0.3;
+
+    pub fn new(params: &Params) -> Chase {
+        Chase {
+            color: params.color(0, WHITE),
+            background: params.color(1, BLACK),
+            period: params.period(2.0),
+        }
+    }
+}
+
+impl Effect for Chase {
+    fn render(&self, time: f64, leds: usize) -> Vec<Oklab> {
+        let head = cycle(time, self.period);
+        (0..leds)
+            .map(|i| {
+                let behind = (head - i as f32 / leds as f32).rem_euclid(1.0);
+                let t = (1.0 - behind / Chase::TAIL).max(0.0);
+                self.color * t + self.background * (1.0 - t)
+            })
+            .collect()
+    }
+}
+
+/// Two colors that flow into each other along the zone, mixed in the Oklab space.
+pub struct OklabWave {
+    color_1: Oklab,
+    color_2: Oklab,
+    period: f32,
+}
+
+impl OklabWave {
+    pub fn new(params: &Params) -> OklabWave {
+        OklabWave {
+            color_1: params.color(0, Oklab::new(0.900, -0.304, 0.151)),
+            color_2: params.color(1, Oklab::new(0.900, 0.094, 0.327)),
+            period: params.period(15.0),
+        }
+    }
+}
+
+impl Effect for OklabWave {
+    fn render(&self, time: f64, leds: usize) -> Vec<Oklab> {
+        let time_phase = cycle(time, self.period) * TAU;
+        (0..leds)
+            .map(|i| {
+                let space_phase = i as f32 / leds as f32 * TAU;
+                let t = (time_phase + space_phase).sin() * 0.5 + 0.5;
+                self.color_1 * t + self.color_2 * (1.0 - t)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_effect_fills_any_zone() {
+        let registry = EffectRegistry::new();
+        assert_eq!(registry.names().count(), 8);
+        for name in registry.names() {
+            let effect = registry.create(name, &Params::default()).unwrap();
+            for leds in [0, 1, 5, 60] {
+                for time in [0.0, 0.35, 100.0] {
+                    let colors = effect.render(time, leds);
+                    assert_eq!(colors.len(), leds, "{name}");
+                    assert!(
+                        colors
+                            .iter()
+                            .all(|c| c.l.is_finite() && c.a.is_finite() && c.b.is_finite()),
+                        "{name} rendered {colors:?}"
+                    );
+                }
+            }
+        }
+        assert!(registry.create("disco", &Params::default()).is_none());
+    }
+
+    #[test]
+    fn effects_keep_their_pace_over_time() {
+        let registry = EffectRegistry::new();
+        let params = Params {
+            period: Some(2.0),
+            ..Params::default()
+        };
+        // Sparkle picks other LEDs in every period
+        for name in registry.names().filter(|&name| name != "sparkle") {
+            let effect = registry.create(name, &params).unwrap();
+            // Ten days later, after a whole number of periods
+            let later = effect.render(0.35 + 432_000.0, 60);
+            for (now, later) in effect.render(0.35, 60).iter().zip(later) {
+                let diff =
+                    (now.l - later.l).abs() + (now.a - later.a).abs() + (now.b - later.b).abs();
+                assert!(diff < 1e-4, "{name} drifted from {now:?} to {later:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn effects_use_the_given_colors() {
+        let red = Oklab::new(0.6, 0.2, 0.1);
+        let blue = Oklab::new(0.5, -0.03, -0.3);
+        let params = Params {
+            colors: vec![red, blue],
+            period: Some(1.0),
+        };
+        assert_eq!(Solid::new(&params).render(0.0, 2), [red; 2]);
+
+        let gradient = Gradient::new(&params).render(0.0, 3);
+        assert_eq!(gradient[0], red);
+        assert_eq!(gradient[2], blue);
+
+        // The head of the chase is on the first LED at the start of each period
+        let chase = Chase::new(&params).render(3.0, 10);
+        assert_eq!(chase[0], red);
+        assert_eq!(chase[5], blue);
+    }
+}
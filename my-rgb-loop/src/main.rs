@@ -8,7 +8,16 @@
 //!
 //! ## Customize the lighting scheme
 //!
-//! Edit the file `state_machine.rs` to create a lighting scheme.
+//! Copy `default-config.toml` to `my-rgb-loop.toml` in the working directory and edit it. It selects the devices, the
+//! effects and their colors in each state, which can be stacked in layers. The effects are listed in `effect.rs`, where
+//! new ones can be added by implementing the `Effect` trait and registering them.
+//!
+//! Changes are applied when the file is saved, as long as it is valid: otherwise the error is written to the log and
+//! the current scheme keeps running.
+//!
+//! The file also declares the states of the lighting and the transitions between them, which are triggered by the
+//! display, the lock of the session, the idle time, the time of day or custom signals. A signal is sent by writing its
+//! name on a line of `my-rgb-loop.signal` in the working directory, for example with `echo party > my-rgb-loop.signal`.
 //!
 //! ## Troubleshooting
 //!
@@ -19,13 +28,31 @@
 // Hide the console window
 #![windows_subsystem = "windows"]
 
+mod compositor;
+mod config;
+mod effect;
 mod state_machine;
+mod target;
+mod transition;
+mod trigger;
 use crate::state_machine::StateMachine;
 
-use orgb::{Connection, Request, Response};
+use crate::config::{Config, ConfigWatcher, DEFAULT_CONFIG};
+use crate::effect::EffectRegistry;
+use crate::trigger::{Environment, Event};
+use orgb::{Connection, DeviceRegistry};
+use std::path::Path;
+use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 
+/// The configuration file, in the working directory.
+const CONFIG_PATH: &str = "my-rgb-loop.toml";
+/// The file where custom signals are written, one per line, in the working directory.
+const SIGNAL_PATH: &str = "my-rgb-loop.signal";
+/// Where the signal file is moved before it is read.
+const TAKEN_SIGNAL_PATH: &str = "my-rgb-loop.signal.taken";
+
 fn main() {
     let _ = simplelog::WriteLogger::init(
         log::LevelFilter::Info,
@@ -35,44 +62,74 @@ fn main() {
 
     log_panics::init();
 
-    let mut serv = Connection::start("127.0.0.1:6742");
-    serv.send(Request::SetClientName("My RGB loop yay"));
+    let (mut serv, protocol_version) = Connection::builder()
+        .client_name("My RGB loop yay")
+        .reconnect(true)
+        .retry_forever()
+        .connect()
+        .expect("Could not connect to the OpenRGB server");
+    log::info!("Using protocol version: {protocol_version}");
 
-    // Resuest a protocol version
-    log::info!("Requesting protocol version 0...");
-    serv.send(Request::ProtocolVersion(0));
-    match serv.recv() {
-        Response::ProtocolVersion(v) => log::info!("Received protocol version: {v}"),
-        other => panic!("Unexpected response: {other:?}"),
-    }
-
-    let mut state_machine = StateMachine::new();
+    let effects = EffectRegistry::new();
+    let mut config_watcher = ConfigWatcher::new(CONFIG_PATH);
+    let config = if Path::new(CONFIG_PATH).exists() {
+        log::info!("Loading {CONFIG_PATH}");
+        Config::load(CONFIG_PATH, &effects)
+    } else {
+        log::info!("{CONFIG_PATH} not found, using the default configuration");
+        Config::parse(DEFAULT_CONFIG, &effects)
+    };
+    let config = config.unwrap_or_else(|e| panic!("Invalid configuration {CONFIG_PATH}: {e}"));
+    let (event_tx, event_rx) = mpsc::channel();
+    trigger::forward_system_events(event_tx.clone());
+    let mut state_machine = StateMachine::with_events(event_rx).with_config(config);
+    let mut registry = DeviceRegistry::new();
 
     loop {
+        for state in serv.state_changes() {
+            log::info!("Connection state changed to {state:?}");
+        }
+
         // Controllers have been updated, they need to be requested again
-        if serv.devices_updated_reset() {
-            // Request the number of controllers
-            serv.send(Request::ControllerCount);
-            let controller_count = match serv.recv() {
-                Response::ControllerCount(c) => c,
-                other => panic!("Unexpected response: {other:?}"),
-            };
+        match registry.poll(&mut serv) {
+            Ok(Some(diff)) if !diff.is_empty() => {
+                log::info!("Controllers changed: {diff:#?}");
+                log::info!("Available controllers: {:#?}", registry.controllers());
+                state_machine.controllers_updated(registry.controllers());
+            }
+            Ok(_) => {}
+            // The registry requests the controllers again at the next poll
+            Err(e) => log::warn!("Could not request the controllers: {e}"),
+        }
+
+        // Switch to the new lighting scheme when the configuration file is saved
+        match config_watcher.poll(&effects) {
+            Some(Ok(config)) => {
+                log::info!("Reloaded {CONFIG_PATH}");
+                state_machine.reload(config);
+            }
+            Some(Err(e)) => {
+                log::error!("Invalid configuration {CONFIG_PATH}, keeping the current one: {e}")
+            }
+            None => {}
+        }
 
-            // Collect all the controllers data
-            let mut new_controllers = Vec::new();
-            for controller_idx in 0..controller_count {
-                serv.send(Request::ControllerData { controller_idx });
-                match serv.recv() {
-                    Response::ControllerData(c) => new_controllers.push(c),
-                    other => panic!("Unexpected response: {other:?}"),
+        // Pass on the signals, so that they are only sent once. The file is moved before it is read, so that the
+        // signals written in the meantime go to a new file instead of being removed along with it.
+        if std::fs::rename(SIGNAL_PATH, TAKEN_SIGNAL_PATH).is_ok() {
+            match std::fs::read_to_string(TAKEN_SIGNAL_PATH) {
+                Ok(signals) => {
+                    for signal in signals.lines().map(str::trim).filter(|s| !s.is_empty()) {
+                        event_tx.send(Event::Signal(signal.into())).unwrap();
+                    }
                 }
+                Err(e) => log::warn!("Could not read the signals: {e}"),
             }
-            log::info!("Available controllers: {new_controllers:#?}");
-            state_machine.controllers_updated(&new_controllers);
+            let _ = std::fs::remove_file(TAKEN_SIGNAL_PATH);
         }
 
         // Step the state machine and update the colors
-        state_machine.update(&mut serv);
+        state_machine.update(&mut serv, &Environment::now());
 
         // Wait a bit
         thread::sleep(Duration::from_millis(100))
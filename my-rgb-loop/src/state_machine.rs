@@ -1,123 +1,647 @@
-use orgb::{Connection, ControllerData, ControllerType, Request, Rgb};
-use palette::{IntoColor, LinSrgb, Oklab, Srgb};
-use sleep_notifier::{self, Event};
-use std::f32::consts::TAU;
+use crate::config::{Config, DEFAULT_CONFIG};
+use crate::effect::EffectRegistry;
+use crate::target::{Frame, Target};
+use crate::transition::{BlendSpace, Easing, Fade};
+use crate::trigger::{Environment, Event};
+use orgb::{Connection, ControllerData};
 use std::sync::mpsc;
 
-enum State {
-    Normal { ticks: u32 },
-    Wake { ticks: u32, ticks_max: u32 },
-    Sleep,
+/// Duration of a step of the state machine, in seconds.
+const TICK: f32 = 0.1;
+/// Number of steps of the crossfade to a new lighting scheme.
+const CROSSFADE_TICKS: u32 = 10;
+/// The crossfade to a new lighting scheme.
+const CROSSFADE: Fade = Fade {
+    duration: CROSSFADE_TICKS as f32 * TICK,
+    easing: Easing::Linear,
+    space: BlendSpace::Oklab,
+};
+
+/// A state of the lighting scheme, with the number of steps since it was entered.
+#[derive(Clone, Copy)]
+struct Active {
+    state: usize,
+    ticks: u32,
+}
+
+impl Active {
+    fn new(state: usize) -> Active {
+        Active { state, ticks: 0 }
+    }
+}
+
+/// A state that is fading out, with the number of steps since the start of the fade.
+struct Fading {
+    from: Active,
+    /// The fade that was still running when this one started, which the state that is fading out is still fading in
+    /// with.
+    previous: Option<Box<Fading>>,
+    fade: Fade,
+    ticks: u32,
+}
+
+impl Fading {
+    /// Fade out of a state, continuing the fade that was still running into it, if any.
+    fn new(from: Active, previous: Option<Fading>, fade: Fade) -> Fading {
+        Fading {
+            from,
+            previous: previous.map(Box::new),
+            fade,
+            ticks: 0,
+        }
+    }
+
+    fn step(&mut self) {
+        self.from.ticks += 1;
+        self.ticks += 1;
+        if let Some(previous) = &mut self.previous {
+            previous.step();
+            if previous.is_done() {
+                self.previous = None;
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.fade.is_done(self.ticks as f32 * TICK)
+    }
+
+    /// Render the frame of the state that is fading out, as it is shown at this step.
+    fn render(&self, scheme: &Scheme, controllers: &[ControllerData]) -> Frame {
+        let mut frame = Frame::new(controllers);
+        scheme.render(self.from, &mut frame, controllers);
+        match &self.previous {
+            Some(previous) => {
+                previous.mix(previous.render(scheme, controllers), frame, controllers)
+            }
+            None => frame,
+        }
+    }
+
+    /// Blend the frame of the state that is fading out with the frame of the new state.
+    fn mix(&self, from: Frame, to: Frame, controllers: &[ControllerData]) -> Frame {
+        let elapsed = self.ticks as f32 * TICK;
+        from.mix(
+            to,
+            |from, to| self.fade.blend(from, to, elapsed),
+            controllers,
+        )
+    }
 }
 
 pub struct StateMachine {
-    // Display status update receiver
-    display_event_rx: mpsc::Receiver<Event>,
-    // Index of the dram light controller
-    dram_idx: Option<u32>,
+    // Event receiver
+    event_rx: mpsc::Receiver<Event>,
+    // Current lighting scheme
+    scheme: Scheme,
+    // Previous lighting scheme, while fading out in its last state
+    previous: Option<(Scheme, Fading)>,
+    // Controllers of the last scan
+    controllers: Vec<ControllerData>,
     // Current state
-    state: State,
+    state: Active,
+    // Previous state, while fading out during a transition
+    fade: Option<Fading>,
+    // Environment of the last step
+    environment: Option<Environment>,
 }
 
 impl StateMachine {
-    pub fn new() -> StateMachine {
+    /// Create a state machine whose transitions are triggered by the events sent through the given receiver, with
+    /// the default configuration.
+    pub fn with_events(event_rx: mpsc::Receiver<Event>) -> StateMachine {
+        let config = Config::parse(DEFAULT_CONFIG, &EffectRegistry::new()).unwrap();
         StateMachine {
-            display_event_rx: sleep_notifier::start(),
-            dram_idx: None,
-            state: State::Normal { ticks: 0 },
+            event_rx,
+            state: Active::new(config.initial_state),
+            scheme: Scheme::new(config),
+            previous: None,
+            controllers: Vec::new(),
+            fade: None,
+            environment: None,
         }
     }
 
+    /// Set the lighting scheme, starting in its initial state.
+    pub fn with_config(mut self, config: Config) -> StateMachine {
+        self.state = Active::new(config.initial_state);
+        self.scheme = Scheme::new(config);
+        self.scheme.select(&self.controllers);
+        self.previous = None;
+        self.fade = None;
+        self
+    }
+
+    /// Switch to a new lighting scheme, with a crossfade from the current one. The state of the same name is kept,
+    /// otherwise the new scheme starts in its initial state.
+    pub fn reload(&mut self, config: Config) {
+        let name = &self.scheme.config.states[self.state.state].name;
+        let state = match config.states.iter().position(|s| s.name == *name) {
+            Some(state) => Active {
+                state,
+                ..self.state
+            },
+            None => Active::new(config.initial_state),
+        };
+        let mut scheme = Scheme::new(config);
+        scheme.select(&self.controllers);
+        // A crossfade that is still running is cut short, while a transition goes on in the previous scheme
+        let previous = std::mem::replace(&mut self.scheme, scheme);
+        let fade = Fading::new(self.state, self.fade.take(), CROSSFADE);
+        self.previous = Some((previous, fade));
+        self.state = state;
+    }
+
     /// Signal to the state machine that the controller have been updated
     pub fn controllers_updated(&mut self, controllers: &[ControllerData]) {
-        // Find the index of the dram light controller
-        self.dram_idx = controllers
-            .iter()
-            .position(|c| c.ty == ControllerType::Dram)
-            .map(|p| p as u32);
+        // The indices may have changed, so select the devices of each group again
+        self.controllers = controllers.to_vec();
+        self.scheme.select(controllers);
+        if let Some((previous, _)) = &mut self.previous {
+            previous.select(controllers);
+        }
     }
 
-    /// Step the state machine
-    pub fn update(&mut self, serv: &mut Connection) {
-        // Get the current events
-        let event = match self.display_event_rx.try_recv() {
-            Ok(e) => {
-                log::info!("Display status updated to {e:?}");
-                Some(e)
+    /// Step the state machine, given the state of the computer at this step
+    pub fn update(&mut self, serv: &mut Connection, environment: &Environment) {
+        self.state.ticks += 1;
+        if let Some(fade) = &mut self.fade {
+            fade.step();
+            if fade.is_done() {
+                self.fade = None;
             }
-            Err(mpsc::TryRecvError::Empty) => None,
-            Err(mpsc::TryRecvError::Disconnected) => panic!("Sender has been disconnected"),
-        };
+        }
 
-        // Update the current state
-        match &mut self.state {
-            State::Normal { ticks } => {
-                *ticks += 1;
-                if let Some(Event::Off | Event::Dimmed) = event {
-                    self.state = State::Sleep // Transition to sleep
-                }
+        // Take the transitions triggered by the events
+        loop {
+            let event = match self.event_rx.try_recv() {
+                Ok(e) => e,
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => panic!("Sender has been disconnected"),
+            };
+            log::info!("Received event {event:?}");
+            self.trigger(serv, Some(&event), environment, environment);
+        }
+
+        // Take the transitions triggered by the changes of the environment
+        let before = self
+            .environment
+            .replace(*environment)
+            .unwrap_or(*environment);
+        self.trigger(serv, None, &before, environment);
+
+        // Update the lights, while fading out the previous state and the previous scheme
+        let mut frame = Frame::new(&self.controllers);
+        self.scheme
+            .render(self.state, &mut frame, &self.controllers);
+        if let Some(fade) = &self.fade {
+            let from = fade.render(&self.scheme, &self.controllers);
+            frame = fade.mix(from, frame, &self.controllers);
+        }
+        if let Some((previous, fade)) = &mut self.previous {
+            fade.step();
+            let from = fade.render(previous, &self.controllers);
+            frame = fade.mix(from, frame, &self.controllers);
+            if fade.is_done() {
+                self.previous = None;
             }
-            State::Sleep => {
-                if let Some(Event::On) = event {
-                    self.state = State::Wake {
-                        ticks: 0,
-                        ticks_max: 5,
-                    } // Transition to wake
+        }
+        if let Err(e) = frame.send(serv, &self.controllers) {
+            log::warn!("Could not update the LEDs: {e}");
+        }
+    }
+
+    /// Take the first transition from the current state that fires, if any. Transitions to the current state are
+    /// ignored.
+    fn trigger(
+        &mut self,
+        serv: &mut Connection,
+        event: Option<&Event>,
+        before: &Environment,
+        after: &Environment,
+    ) {
+        let config = &self.scheme.config;
+        let Some(transition) = config.transitions.iter().find(|t| {
+            t.to != self.state.state
+                && t.from
+                    .as_ref()
+                    .is_none_or(|f| f.contains(&self.state.state))
+                && t.trigger.fires(event, before, after)
+        }) else {
+            return;
+        };
+
+        let state = &config.states[transition.to];
+        log::info!("Entering state {:?}", state.name);
+        load_profile(serv, state.profile.as_deref());
+        // A transition that is still running goes on in the state that is fading out, so that the colors do not jump
+        let fade = Fading::new(self.state, self.fade.take(), transition.fade);
+        self.fade = (!fade.is_done()).then_some(fade);
+        self.state = Active::new(transition.to);
+    }
+}
+
+/// Groups of devices and their effects in each state.
+struct Scheme {
+    config: Config,
+    // Currently selected devices of each group
+    targets: Vec<Vec<Target>>,
+}
+
+impl Scheme {
+    fn new(config: Config) -> Scheme {
+        Scheme {
+            config,
+            targets: Vec::new(),
+        }
+    }
+
+    fn select(&mut self, controllers: &[ControllerData]) {
+        self.targets = self
+            .config
+            .groups
+            .iter()
+            .map(|group| {
+                group
+                    .selector
+                    .resolve(controllers)
+                    .iter()
+                    .map(|s| Target::new(s, &controllers[s.controller_idx as usize]))
+                    .collect()
+            })
+            .collect();
+    }
+
+    fn render(&self, active: Active, frame: &mut Frame, controllers: &[ControllerData]) {
+        let time = f64::from(active.ticks) * f64::from(TICK);
+        let layers = &self.config.states[active.state].layers;
+        // Devices are left alone in the states without a layer. Groups are stacked in the order of their names.
+        for (layers, targets) in layers.iter().zip(&self.targets) {
+            for layer in layers {
+                let mut top = frame.blank();
+                for target in targets {
+                    target.render(&mut top, |leds| layer.effect.render(time, leds));
                 }
-            }
-            State::Wake { ticks, ticks_max } => {
-                *ticks += 1;
-                if ticks == ticks_max {
-                    self.state = State::Normal { ticks: 0 } // Transition to normal
+                if let Some(mask) = &layer.mask {
+                    top.mask(&mask.resolve(controllers));
                 }
+                frame.composite(top, layer.blend, layer.opacity, controllers);
             }
         }
+    }
+}
 
-        // Update the lights of the dram
-        if let Some(controller_idx) = self.dram_idx {
-            let dram_colors = match self.state {
-                State::Sleep => dram_color_asleep(),
-                State::Normal { ticks: counter } => dram_color_normal(counter),
-                State::Wake { ticks, ticks_max } => dram_color_wake(ticks, ticks_max),
-            };
-            serv.send(Request::UpdateLeds {
+fn load_profile(serv: &mut Connection, profile: Option<&str>) {
+    let Some(profile) = profile else { return };
+    // Older servers do not know about profiles
+    if serv.protocol_version() < 2 {
+        log::warn!("Cannot load profile {profile:?} with protocol version < 2");
+        return;
+    }
+    log::info!("Loading profile {profile:?}");
+    if let Err(e) = serv.load_profile(profile) {
+        log::warn!("Could not load profile {profile:?}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orgb::{mock_controller, ControllerType, MockServer, Request, Rgb};
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    const DRAM_BY_NAME: &str = r#"
+        devices = { dram = "name=DRAM" }
+        states.normal.effects = { dram = { effect = "oklab_wave" } }
+    "#;
+    const STRIP: &str = r#"
+        devices = { strip = "type=ledstrip" }
+        states.normal.effects = { strip = { effect = "oklab_wave" } }
+    "#;
+    const SECOND_ZONE: &str = r#"
+        devices = { strip = 'zone="Second zone"' }
+        states.normal.effects = { strip = { effect = "oklab_wave" } }
+    "#;
+    const PROFILES: &str = r#"
+        states.normal = { profile = "Bright" }
+        states.sleep = { profile = "Dark" }
+        transitions = [
+            { from = "normal", to = "sleep", on = "display_off" },
+            { from = "sleep", to = "normal", on = "display_on" },
+        ]
+    "#;
+    const TRIGGERS: &str = r##"
+        initial_state = "day"
+        devices = { dram = "type=dram" }
+        states.day.effects = { dram = { effect = "solid", colors = ["#ffffff"] } }
+        states.night.effects = { dram = { effect = "solid", colors = ["#0000ff"] } }
+        states.away.effects = { dram = { effect = "solid", colors = ["#000000"] } }
+        states.party.effects = { dram = { effect = "solid", colors = ["#ff0000"] } }
+        transitions = [
+            { to = "party", on = "signal party" },
+            { from = "day", to = "night", on = "time 22:00" },
+            { from = ["day", "night"], to = "away", on = "idle 300" },
+            { from = "away", to = "day", on = "active" },
+        ]
+    "##;
+
+    fn config(text: &str) -> Config {
+        Config::parse(text, &EffectRegistry::new()).unwrap()
+    }
+
+    fn sent_colors(server: &MockServer) -> (u32, Vec<Rgb>) {
+        match server.wait_for_requests(1, TIMEOUT).pop() {
+            Some(Request::UpdateLeds {
                 controller_idx,
-                colors: &dram_colors.map(|oklab| {
-                    let srgb: Srgb = oklab.into_color();
-                    let srgb: LinSrgb<u8> = srgb.into_linear().into_format();
-                    Rgb(srgb.red, srgb.green, srgb.blue)
-                }),
-            });
+                colors,
+            }) => (controller_idx, colors.into_owned()),
+            other => panic!("Unexpected request: {other:?}"),
         }
     }
-}
 
-// Color picker: https://observablehq.com/@shan/oklab-color-wheel
+    fn environment(idle: u64, time_of_day: u32) -> Environment {
+        Environment {
+            idle: Duration::from_secs(idle),
+            time_of_day,
+        }
+    }
+
+    #[test]
+    fn lights_the_dram_and_sleeps_with_the_display() {
+        let server = MockServer::start(vec![
+            mock_controller(ControllerType::Gpu, "GPU", 1),
+            mock_controller(ControllerType::Dram, "DRAM", 5),
+        ]);
+        let mut serv = Connection::start(server.addr());
+        let controllers = serv.list_controllers().unwrap();
+        server.take_requests();
+
+        let (event_tx, event_rx) = mpsc::channel();
+        let mut state_machine = StateMachine::with_events(event_rx);
+        state_machine.controllers_updated(&controllers);
 
-fn dram_color_normal(ticks: u32) -> [Oklab; 5] {
-    let time_phase = (ticks % 150) as f32 / 150.0 * TAU;
-    let color_1 = Oklab::new(0.900, -0.304, 0.151);
-    let color_2 = Oklab::new(0.900, 0.094, 0.327);
-    let mut result = [Oklab::default(); 5];
-    for (i, c) in result.iter_mut().enumerate() {
-        let space_phase = i as f32 / 5.0 * TAU;
-        let t = (time_phase + space_phase).sin() * 0.5 + 0.5;
-        *c = color_1 * t + color_2 * (1.0 - t);
+        state_machine.update(&mut serv, &Environment::default());
+        let (controller_idx, awake) = sent_colors(&server);
+        assert_eq!(controller_idx, 1);
+        assert_eq!(awake.len(), 5);
+
+        event_tx.send(Event::DisplayOff).unwrap();
+        state_machine.update(&mut serv, &Environment::default());
+        let (_, asleep) = sent_colors(&server);
+        assert!(asleep.iter().all(|c| *c == asleep[0]));
+        assert_ne!(asleep, awake);
     }
-    result
-}
 
-fn dram_color_asleep() -> [Oklab; 5] {
-    let orange = Oklab::new(0.5, 0.24, 0.29);
-    [orange; 5]
-}
+    #[test]
+    fn follows_the_dram_across_rescans() {
+        let dram = mock_controller(ControllerType::Dram, "DRAM", 5);
+        let other_dram = mock_controller(ControllerType::Dram, "Other DRAM", 5);
+        let server = MockServer::start(Vec::new());
+        let mut serv = Connection::start(server.addr());
+
+        let (_event_tx, event_rx) = mpsc::channel();
+        let mut state_machine =
+            StateMachine::with_events(event_rx).with_config(config(DRAM_BY_NAME));
+        state_machine.controllers_updated(std::slice::from_ref(&dram));
+
+        // Another DRAM is detected before ours, which shifts its index
+        state_machine.controllers_updated(&[other_dram.clone(), dram]);
+        state_machine.update(&mut serv, &Environment::default());
+        assert_eq!(sent_colors(&server).0, 1);
+
+        // Our DRAM is gone, so nothing is sent to the other one
+        state_machine.controllers_updated(&[other_dram]);
+        state_machine.update(&mut serv, &Environment::default());
+        serv.controller_count().unwrap();
+        assert_eq!(server.take_requests(), [Request::ControllerCount]);
+    }
+
+    #[test]
+    fn lights_every_selected_dram() {
+        let server = MockServer::start(Vec::new());
+        let mut serv = Connection::start(server.addr());
+
+        let (_event_tx, event_rx) = mpsc::channel();
+        let mut state_machine = StateMachine::with_events(event_rx);
+        state_machine.controllers_updated(&[
+            mock_controller(ControllerType::Dram, "DRAM 1", 5),
+            mock_controller(ControllerType::Gpu, "GPU", 1),
+            mock_controller(ControllerType::Dram, "DRAM 2", 5),
+        ]);
+        state_machine.update(&mut serv, &Environment::default());
+        let requests = server.wait_for_requests(2, TIMEOUT);
+        let targets: Vec<_> = requests
+            .iter()
+            .map(|r| match r {
+                Request::UpdateLeds { controller_idx, .. } => *controller_idx,
+                other => panic!("Unexpected request: {other:?}"),
+            })
+            .collect();
+        assert_eq!(targets, [0, 2]);
+    }
+
+    #[test]
+    fn fills_every_selected_led_zone_by_zone() {
+        let server = MockServer::start(Vec::new());
+        let mut serv = Connection::start(server.addr());
+
+        // Two zones of 5 LEDs, with the second one split from the first
+        let mut strip = mock_controller(ControllerType::LedStrip, "Strip", 10);
+        let mut second_zone = strip.zones[0].clone();
+        second_zone.name = "Second zone".into();
+        second_zone.leds_count = 5;
+        strip.zones[0].leds_count = 5;
+        strip.zones.push(second_zone);
+        strip.colors = vec![Rgb(1, 2, 3); 10];
+
+        let (_event_tx, event_rx) = mpsc::channel();
+        let mut state_machine = StateMachine::with_events(event_rx).with_config(config(STRIP));
+        state_machine.controllers_updated(std::slice::from_ref(&strip));
+        state_machine.update(&mut serv, &Environment::default());
+        let (_, colors) = sent_colors(&server);
+        assert_eq!(colors.len(), 10);
+        assert_eq!(colors[..5], colors[5..]);
+
+        // The LEDs that are not selected keep their colors
+        let (_event_tx, event_rx) = mpsc::channel();
+        let mut state_machine =
+            StateMachine::with_events(event_rx).with_config(config(SECOND_ZONE));
+        state_machine.controllers_updated(&[strip]);
+        state_machine.update(&mut serv, &Environment::default());
+        let (_, partial) = sent_colors(&server);
+        assert_eq!(partial[..5], [Rgb(1, 2, 3); 5]);
+        assert_eq!(partial[5..], colors[5..]);
+    }
+
+    #[test]
+    fn crossfades_to_a_reloaded_config() {
+        let server = MockServer::start(Vec::new());
+        let mut serv = Connection::start(server.addr());
+        let controllers = [mock_controller(ControllerType::Dram, "DRAM", 5)];
+        let solid = |color: &str| {
+            config(&format!(
+                "devices = {{ dram = 'type=dram' }}\n\
+                 states.normal.effects = {{ dram = {{ effect = 'solid', colors = ['{color}'] }} }}"
+            ))
+        };
+
+        let (_event_tx, event_rx) = mpsc::channel();
+        let mut state_machine = StateMachine::with_events(event_rx).with_config(solid("#ff0000"));
+        state_machine.controllers_updated(&controllers);
+        state_machine.update(&mut serv, &Environment::default());
+        let (_, red) = sent_colors(&server);
+        assert_eq!(red, [Rgb(255, 0, 0); 5]);
+
+        state_machine.reload(solid("#0000ff"));
+        let mut fade = Vec::new();
+        for _ in 0..CROSSFADE_TICKS + 1 {
+            state_machine.update(&mut serv, &Environment::default());
+            fade.push(sent_colors(&server).1[0]);
+        }
+        let blue = fade[CROSSFADE_TICKS as usize];
+        assert!(blue.2 > 250 && blue.0 < 5);
+        assert_eq!(fade[CROSSFADE_TICKS as usize - 1], blue);
+        // The red fades out while the blue fades in
+        assert!(fade
+            .windows(2)
+            .all(|w| w[0].0 >= w[1].0 && w[0].2 <= w[1].2));
+        assert!(fade[0] != Rgb(255, 0, 0) && fade[0] != blue);
+    }
+
+    #[test]
+    fn loads_profiles_with_the_display() {
+        let server = MockServer::start(vec![mock_controller(ControllerType::Gpu, "GPU", 1)]);
+        let mut serv = Connection::start(server.addr());
+        serv.negotiate_protocol_version().unwrap();
+        server.take_requests();
+
+        let (event_tx, event_rx) = mpsc::channel();
+        let mut state_machine = StateMachine::with_events(event_rx).with_config(config(PROFILES));
+
+        event_tx.send(Event::DisplayOff).unwrap();
+        state_machine.update(&mut serv, &Environment::default());
+        event_tx.send(Event::DisplayOn).unwrap();
+        state_machine.update(&mut serv, &Environment::default());
+        assert_eq!(
+            server.wait_for_requests(2, TIMEOUT),
+            [
+                Request::LoadProfile("Dark".into()),
+                Request::LoadProfile("Bright".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn follows_the_triggers_of_the_config() {
+        let server = MockServer::start(Vec::new());
+        let mut serv = Connection::start(server.addr());
+        let (event_tx, event_rx) = mpsc::channel();
+        let mut state_machine = StateMachine::with_events(event_rx).with_config(config(TRIGGERS));
+        state_machine.controllers_updated(&[mock_controller(ControllerType::Dram, "DRAM", 1)]);
+        let mut step = |environment: Environment| {
+            state_machine.update(&mut serv, &environment);
+            sent_colors(&server).1[0]
+        };
+
+        assert_eq!(step(environment(0, 21 * 60 + 59)), Rgb(255, 255, 255));
+        assert_eq!(step(environment(0, 22 * 60)), Rgb(0, 0, 255));
+        assert_eq!(step(environment(299, 22 * 60)), Rgb(0, 0, 255));
+        assert_eq!(step(environment(300, 22 * 60)), Rgb(0, 0, 0));
+        assert_eq!(step(environment(0, 22 * 60)), Rgb(255, 255, 255));
+
+        // Custom signals lead to the party from any state
+        event_tx.send(Event::Signal("disco".into())).unwrap();
+        assert_eq!(step(environment(0, 22 * 60)), Rgb(255, 255, 255));
+        event_tx.send(Event::Signal("party".into())).unwrap();
+        assert_eq!(step(environment(0, 22 * 60)), Rgb(255, 0, 0));
+        assert_eq!(step(environment(300, 22 * 60)), Rgb(255, 0, 0));
+    }
+
+    #[test]
+    fn fades_during_transitions() {
+        let server = MockServer::start(Vec::new());
+        let mut serv = Connection::start(server.addr());
+        let (event_tx, event_rx) = mpsc::channel();
+        let mut state_machine = StateMachine::with_events(event_rx).with_config(config(
+            r##"
+            devices = { dram = "type=dram" }
+            states.normal.effects = { dram = { effect = "solid", colors = ["#ff0000"] } }
+            states.sleep.effects = { dram = { effect = "solid", colors = ["#0000ff"] } }
+            transitions = [{ to = "sleep", on = "lock", duration = 0.5 }]
+            "##,
+        ));
+        state_machine.controllers_updated(&[mock_controller(ControllerType::Dram, "DRAM", 1)]);
+
+        event_tx.send(Event::Lock).unwrap();
+        let mut fade = Vec::new();
+        for _ in 0..6 {
+            state_machine.update(&mut serv, &Environment::default());
+            fade.push(sent_colors(&server).1[0]);
+        }
+        assert_eq!(fade[0], Rgb(255, 0, 0));
+        assert!(fade.windows(2).all(|w| w[0].0 > w[1].0 && w[0].2 < w[1].2));
+        assert_eq!(fade[5], Rgb(0, 0, 255));
+    }
+
+    #[test]
+    fn carries_on_a_fade_cut_short_by_a_transition() {
+        let server = MockServer::start(Vec::new());
+        let mut serv = Connection::start(server.addr());
+        let (event_tx, event_rx) = mpsc::channel();
+        let mut state_machine = StateMachine::with_events(event_rx).with_config(config(
+            r##"
+            devices = { dram = "type=dram" }
+            states.normal.effects = { dram = { effect = "solid", colors = ["#ff0000"] } }
+            states.sleep.effects = { dram = { effect = "solid", colors = ["#0000ff"] } }
+            states.party.effects = { dram = { effect = "solid", colors = ["#00ff00"] } }
+            transitions = [
+                { to = "sleep", on = "lock", duration = 1 },
+                { to = "party", on = "signal party", duration = 1 },
+            ]
+            "##,
+        ));
+        state_machine.controllers_updated(&[mock_controller(ControllerType::Dram, "DRAM", 1)]);
+        let mut step = || {
+            state_machine.update(&mut serv, &Environment::default());
+            sent_colors(&server).1[0]
+        };
+
+        event_tx.send(Event::Lock).unwrap();
+        let fade: Vec<_> = (0..4).map(|_| step()).collect();
+        assert!(fade[3].0 > fade[3].2);
+
+        // The red that was fading out keeps fading out, instead of jumping to the blue of the state being left
+        event_tx.send(Event::Signal("party".into())).unwrap();
+        let party: Vec<_> = (0..11).map(|_| step()).collect();
+        assert!(party[0].0 > 0 && party[0].0 <= fade[3].0);
+        assert!(party[0].2 >= fade[3].2 && party[0].2 < fade[3].0);
+        assert!(party
+            .windows(2)
+            .all(|w| w[0].0 >= w[1].0 && w[0].1 <= w[1].1));
+        assert_eq!(party[10], Rgb(0, 255, 0));
+    }
 
-fn dram_color_wake(ticks: u32, ticks_max: u32) -> [Oklab; 5] {
-    let orange = Oklab::new(0.5, 0.24, 0.29);
-    let mut result = dram_color_normal(0);
-    let t = ticks as f32 / ticks_max as f32;
-    for c in result.iter_mut() {
-        *c = *c * t + orange * (1.0 - t);
+    #[test]
+    fn stacks_the_layers_of_a_group() {
+        let server = MockServer::start(Vec::new());
+        let mut serv = Connection::start(server.addr());
+        let (_event_tx, event_rx) = mpsc::channel();
+        let mut state_machine = StateMachine::with_events(event_rx).with_config(config(
+            r##"
+            devices = { dram = "type=dram" }
+            states.normal.effects.dram = { effect = "solid", colors = ["#c80000"] }
+            states.normal.layers.dram = [
+                { effect = "solid", colors = ["#0000c8"], blend = "add", mask = 'led="LED 1"' },
+                { effect = "solid", colors = ["#000000"], opacity = 0.5, mask = 'led="LED 2"' },
+            ]
+            "##,
+        ));
+        state_machine.controllers_updated(&[mock_controller(ControllerType::Dram, "DRAM", 3)]);
+        state_machine.update(&mut serv, &Environment::default());
+        let (_, colors) = sent_colors(&server);
+        assert_eq!(colors[..2], [Rgb(200, 0, 0), Rgb(200, 0, 200)]);
+        assert!(colors[2].0.abs_diff(100) <= 1 && colors[2].2 == 0);
     }
-    result
 }
@@ -0,0 +1,196 @@
+use crate::compositor::BlendMode;
+use orgb::{Connection, ControllerData, ProtocolError, Rgb, Selection};
+use palette::{IntoColor, LinSrgb, Oklab, Srgb};
+
+/// The selected LEDs of one controller, grouped by zone so that effects can be laid out along each zone.
+pub struct Target {
+    controller_idx: u32,
+    // Indices of the selected LEDs, one group per zone
+    groups: Vec<Vec<u32>>,
+}
+
+impl Target {
+    /// Resolve a selection against the controller that it was made from.
+    pub fn new(selection: &Selection, controller: &ControllerData) -> Target {
+        // LEDs that do not belong to any zone are gathered in a last group
+        let mut groups = Vec::new();
+        let mut zone_start = 0u32;
+        for zone in controller
+            .zones
+            .iter()
+            .map(|z| z.leds_count)
+            .chain([u32::MAX])
+        {
+            let zone_end = zone_start.saturating_add(zone);
+            let group: Vec<u32> = selection
+                .leds
+                .iter()
+                .copied()
+                .filter(|led| (zone_start..zone_end).contains(led))
+                .collect();
+            if !group.is_empty() {
+                groups.push(group);
+            }
+            zone_start = zone_end;
+        }
+
+        Target {
+            controller_idx: selection.controller_idx,
+            groups,
+        }
+    }
+
+    /// Render the colors of each zone into the frame, with the given function, which receives the number of selected
+    /// LEDs of the zone.
+    pub fn render(&self, frame: &mut Frame, mut render: impl FnMut(usize) -> Vec<Oklab>) {
+        let Some(leds) = frame.controllers.get_mut(self.controller_idx as usize) else {
+            return;
+        };
+        for group in &self.groups {
+            for (&led, color) in group.iter().zip(render(group.len())) {
+                if let Some(c) = leds.get_mut(led as usize) {
+                    *c = Some(color);
+                }
+            }
+        }
+    }
+}
+
+/// The colors of the LEDs of every controller for one step. LEDs without a color are left as they are.
+pub struct Frame {
+    controllers: Vec<Vec<Option<Oklab>>>,
+}
+
+impl Frame {
+    /// Create a frame where no LED has a color.
+    pub fn new(controllers: &[ControllerData]) -> Frame {
+        Frame {
+            controllers: controllers
+                .iter()
+                .map(|c| vec![None; c.leds.len()])
+                .collect(),
+        }
+    }
+
+    /// Create a frame of the same controllers, where no LED has a color.
+    pub fn blank(&self) -> Frame {
+        Frame {
+            controllers: self
+                .controllers
+                .iter()
+                .map(|c| vec![None; c.len()])
+                .collect(),
+        }
+    }
+
+    /// Remove the colors of the LEDs that are not selected.
+    pub fn mask(&mut self, selections: &[Selection]) {
+        for (controller_idx, leds) in self.controllers.iter_mut().enumerate() {
+            let selected: &[u32] = selections
+                .iter()
+                .find(|s| s.controller_idx as usize == controller_idx)
+                .map_or(&[], |s| &s.leds);
+            for (led, color) in leds.iter_mut().enumerate() {
+                if !selected.contains(&(led as u32)) {
+                    *color = None;
+                }
+            }
+        }
+    }
+
+    /// Stack a layer over this frame. The LEDs that have no color in this frame show their current color below the
+    /// layer.
+    pub fn composite(
+        &mut self,
+        layer: Frame,
+        blend: BlendMode,
+        opacity: f32,
+        controllers: &[ControllerData],
+    ) {
+        for ((leds, layer), controller) in self
+            .controllers
+            .iter_mut()
+            .zip(layer.controllers)
+            .zip(controllers)
+        {
+            for ((below, top), current) in
+                leds.iter_mut().zip(layer).zip(current_colors(controller))
+            {
+                if let Some(top) = top {
+                    *below = Some(blend.blend(below.unwrap_or(current), top, opacity));
+                }
+            }
+        }
+    }
+
+    /// Mix two frames, with a function that blends a color of `self` with the color of the same LED in `other`. LEDs
+    /// that have a color in only one of the frames are blended with their current color.
+    pub fn mix(
+        self,
+        other: Frame,
+        blend: impl Fn(Oklab, Oklab) -> Oklab,
+        controllers: &[ControllerData],
+    ) -> Frame {
+        let mixed = self
+            .controllers
+            .into_iter()
+            .zip(other.controllers)
+            .zip(controllers)
+            .map(|((from, to), controller)| {
+                from.into_iter()
+                    .zip(to)
+                    .zip(current_colors(controller))
+                    .map(|((from, to), current)| match (from, to) {
+                        (None, None) => None,
+                        (from, to) => {
+                            let (from, to) = (from.unwrap_or(current), to.unwrap_or(current));
+                            Some(blend(from, to))
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        Frame { controllers: mixed }
+    }
+
+    /// Send the colors of the controllers that have at least one LED with a color.
+    pub fn send(
+        &self,
+        serv: &mut Connection,
+        controllers: &[ControllerData],
+    ) -> Result<(), ProtocolError> {
+        for (controller_idx, (leds, controller)) in
+            self.controllers.iter().zip(controllers).enumerate()
+        {
+            if leds.iter().all(Option::is_none) {
+                continue;
+            }
+            // The LEDs that are not animated are sent along with the others, with the colors that they had
+            let colors: Vec<Rgb> = leds
+                .iter()
+                .zip(current_colors(controller))
+                .map(|(led, current)| to_rgb(led.unwrap_or(current)))
+                .collect();
+            serv.update_leds(controller_idx as u32, &colors)?;
+        }
+        Ok(())
+    }
+}
+
+/// The colors of the LEDs of the controller when it was scanned.
+fn current_colors(controller: &ControllerData) -> impl Iterator<Item = Oklab> + '_ {
+    let colors = controller.colors.iter().map(|&c| from_rgb(c));
+    colors.chain(std::iter::repeat(Oklab::new(0.0, 0.0, 0.0)))
+}
+
+/// Convert a color to the values that are sent to the devices.
+pub fn to_rgb(oklab: Oklab) -> Rgb {
+    let srgb: Srgb = oklab.into_color();
+    let srgb: LinSrgb<u8> = srgb.into_linear().into_format();
+    Rgb(srgb.red, srgb.green, srgb.blue)
+}
+
+/// Convert the values that are sent to the devices to a color, so that [`to_rgb`] gives them back.
+pub fn from_rgb(Rgb(r, g, b): Rgb) -> Oklab {
+    LinSrgb::new(r, g, b).into_format::<f32>().into_color()
+}
@@ -0,0 +1,215 @@
+use palette::{IntoColor, LinSrgb, Oklab, Oklch};
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/// Pace of a fade, which maps the elapsed fraction of the fade to the fraction of the new colors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Easing {
+    /// At a constant pace.
+    #[default]
+    Linear,
+    /// Slow at the start and at the end, along a sine.
+    EaseInOut,
+    /// Slow at the start and at the end, along a cubic, which is steeper in the middle.
+    Cubic,
+}
+
+impl Easing {
+    /// Apply the easing to `t`, between 0 and 1.
+    pub fn ease(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseInOut => (1.0 - (PI * t).cos()) / 2.0,
+            Easing::Cubic if t < 0.5 => 4.0 * t * t * t,
+            Easing::Cubic => 1.0 - (2.0 - 2.0 * t).powi(3) / 2.0,
+        }
+    }
+}
+
+/// The color space where the colors are blended during a fade.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlendSpace {
+    /// Perceptually even, the colors go in a straight line.
+    #[default]
+    Oklab,
+    /// Like mixing lights, the mix of two colors is brighter than in Oklab.
+    LinearSrgb,
+    /// Goes around the hue circle, by the shortest way, so that a fade from red to blue goes through purple rather
+    /// than gray.
+    Oklch,
+}
+
+impl BlendSpace {
+    /// Blend two colors, from all of `from` when `t` is 0 to all of `to` when `t` is 1.
+    pub fn blend(self, from: Oklab, to: Oklab, t: f32) -> Oklab {
+        match self {
+            BlendSpace::Oklab => from * (1.0 - t) + to * t,
+            BlendSpace::LinearSrgb => {
+                let (from, to): (LinSrgb, LinSrgb) = (from.into_color(), to.into_color());
+                (from * (1.0 - t) + to * t).into_color()
+            }
+            BlendSpace::Oklch => {
+                let (from, to): (Oklch, Oklch) = (from.into_color(), to.into_color());
+                // Grays have no hue, so they take the hue of the other color
+                let (from_hue, to_hue) = match (from.chroma < 1e-4, to.chroma < 1e-4) {
+                    (true, false) => (to.hue, to.hue),
+                    (false, true) => (from.hue, from.hue),
+                    _ => (from.hue, to.hue),
+                };
+                let turn = (to_hue - from_hue).into_degrees();
+                Oklch::new(
+                    from.l * (1.0 - t) + to.l * t,
+                    from.chroma * (1.0 - t) + to.chroma * t,
+                    from_hue + turn * t,
+                )
+                .into_color()
+            }
+        }
+    }
+}
+
+/// How to fade from an effect to another.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Fade {
+    /// Duration of the fade, in seconds.
+    pub duration: f32,
+    pub easing: Easing,
+    pub space: BlendSpace,
+}
+
+impl Fade {
+    /// Blend the colors of the two effects, `elapsed` seconds after the start of the fade.
+    pub fn blend(&self, from: Oklab, to: Oklab, elapsed: f32) -> Oklab {
+        self.space
+            .blend(from, to, self.easing.ease(self.progress(elapsed)))
+    }
+
+    /// Returns true once the colors are all of the new effect.
+    pub fn is_done(&self, elapsed: f32) -> bool {
+        self.progress(elapsed) >= 1.0
+    }
+
+    fn progress(&self, elapsed: f32) -> f32 {
+        if self.duration > 0.0 {
+            (elapsed / self.duration).clamp(0.0, 1.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+/// A name of the configuration file, such as an easing or a blend space, that could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownName {
+    pub name: String,
+    /// The valid names.
+    pub expected: &'static [&'static str],
+}
+
+impl fmt::Display for UnknownName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown {:?}, expected one of {:?}",
+            self.name, self.expected
+        )
+    }
+}
+
+impl std::error::Error for UnknownName {}
+
+impl FromStr for Easing {
+    type Err = UnknownName;
+
+    fn from_str(s: &str) -> Result<Easing, UnknownName> {
+        match s {
+            "linear" => Ok(Easing::Linear),
+            "ease_in_out" => Ok(Easing::EaseInOut),
+            "cubic" => Ok(Easing::Cubic),
+            _ => Err(UnknownName {
+                name: s.into(),
+                expected: &["linear", "ease_in_out", "cubic"],
+            }),
+        }
+    }
+}
+
+impl FromStr for BlendSpace {
+    type Err = UnknownName;
+
+    fn from_str(s: &str) -> Result<BlendSpace, UnknownName> {
+        match s {
+            "oklab" => Ok(BlendSpace::Oklab),
+            "linear_srgb" => Ok(BlendSpace::LinearSrgb),
+            "oklch" => Ok(BlendSpace::Oklch),
+            _ => Err(UnknownName {
+                name: s.into(),
+                expected: &["oklab", "linear_srgb", "oklch"],
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::{from_rgb, to_rgb};
+    use orgb::Rgb;
+    use palette::FromColor;
+
+    #[test]
+    fn easings_go_from_0_to_1() {
+        for easing in [Easing::Linear, Easing::EaseInOut, Easing::Cubic] {
+            assert_eq!(easing.ease(0.0), 0.0);
+            assert!((easing.ease(0.5) - 0.5).abs() < 1e-6);
+            assert!((easing.ease(1.0) - 1.0).abs() < 1e-6);
+            let steps: Vec<f32> = (0..=10).map(|i| easing.ease(i as f32 / 10.0)).collect();
+            assert!(steps.windows(2).all(|w| w[0] < w[1]), "{easing:?}");
+        }
+        assert!(Easing::Cubic.ease(0.1) < Easing::EaseInOut.ease(0.1));
+        assert!(Easing::EaseInOut.ease(0.1) < Easing::Linear.ease(0.1));
+    }
+
+    #[test]
+    fn blend_in_each_space() {
+        let (red, blue) = (from_rgb(Rgb(255, 0, 0)), from_rgb(Rgb(0, 0, 255)));
+        for space in [BlendSpace::Oklab, BlendSpace::LinearSrgb, BlendSpace::Oklch] {
+            assert_eq!(to_rgb(space.blend(red, blue, 0.0)), Rgb(255, 0, 0));
+            assert_eq!(to_rgb(space.blend(red, blue, 1.0)), Rgb(0, 0, 255));
+        }
+        let Rgb(r, g, b) = to_rgb(BlendSpace::LinearSrgb.blend(red, blue, 0.5));
+        assert!(r.abs_diff(128) <= 1 && g == 0 && b.abs_diff(128) <= 1);
+
+        // The hue takes the shortest way, through purple rather than through green
+        let purple: Oklch = BlendSpace::Oklch.blend(red, blue, 0.5).into_color();
+        let (red_hue, blue_hue) = (Oklch::from_color(red).hue, Oklch::from_color(blue).hue);
+        assert!((purple.hue - red_hue).into_degrees() < 0.0);
+        assert!((blue_hue - purple.hue).into_degrees() < 0.0);
+
+        // A fade from black keeps the hue of the other color
+        let black = Oklab::new(0.0, 0.0, 0.0);
+        let dark_red: Oklch = BlendSpace::Oklch.blend(black, red, 0.5).into_color();
+        assert!((dark_red.hue - red_hue).into_degrees().abs() < 1e-2);
+    }
+
+    #[test]
+    fn fade_over_its_duration() {
+        let (from, to) = (Oklab::new(0.0, 0.0, 0.0), Oklab::new(1.0, 0.0, 0.0));
+        let fade = Fade {
+            duration: 2.0,
+            ..Fade::default()
+        };
+        assert_eq!(fade.blend(from, to, 1.0), Oklab::new(0.5, 0.0, 0.0));
+        assert!(!fade.is_done(1.9));
+        assert!(fade.is_done(2.0));
+        assert_eq!(fade.blend(from, to, 3.0), to);
+        assert!(Fade::default().is_done(0.0));
+        assert!("ease_in_out".parse::<Easing>().is_ok());
+        assert_eq!(
+            "hsl".parse::<BlendSpace>().unwrap_err().to_string(),
+            "unknown \"hsl\", expected one of [\"oklab\", \"linear_srgb\", \"oklch\"]"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Something that happened to the computer, which may trigger a transition of the state machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    DisplayOff,
+    DisplayOn,
+    DisplayDimmed,
+    Lock,
+    Unlock,
+    /// A signal sent by the user, see `SIGNAL_PATH` in `main.rs`.
+    Signal(String),
+}
+
+impl From<sleep_notifier::Event> for Event {
+    fn from(event: sleep_notifier::Event) -> Event {
+        match event {
+            sleep_notifier::Event::Off => Event::DisplayOff,
+            sleep_notifier::Event::On => Event::DisplayOn,
+            sleep_notifier::Event::Dimmed => Event::DisplayDimmed,
+            sleep_notifier::Event::Lock => Event::Lock,
+            sleep_notifier::Event::Unlock => Event::Unlock,
+        }
+    }
+}
+
+/// Forward the display and session events to the given channel, from a background thread.
+pub fn forward_system_events(tx: mpsc::Sender<Event>) {
+    let rx = sleep_notifier::start();
+    thread::spawn(move || {
+        for event in rx {
+            if tx.send(event.into()).is_err() {
+                return;
+            }
+        }
+        panic!("Sender has been disconnected");
+    });
+}
+
+/// The state of the computer, which is sampled at every step of the state machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Environment {
+    /// Time since the last input of the user.
+    pub idle: Duration,
+    /// Local time of day, in minutes since midnight.
+    pub time_of_day: u32,
+}
+
+impl Environment {
+    /// Sample the state of the computer.
+    pub fn now() -> Environment {
+        let (hours, minutes) = sleep_notifier::local_time();
+        Environment {
+            idle: sleep_notifier::idle_time(),
+            time_of_day: hours * 60 + minutes,
+        }
+    }
+}
+
+/// What makes a transition of the state machine happen, written in the configuration file as one of `display_off`,
+/// `display_on`, `display_dimmed`, `lock`, `unlock`, `idle <seconds>`, `active`, `time <hh:mm>` or
+/// `signal <name>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    Event(Event),
+    /// The user has not touched the keyboard or the mouse for this long.
+    Idle(Duration),
+    /// The user touched the keyboard or the mouse.
+    Active,
+    /// The local time reaches or passes this time of day, in minutes since midnight.
+    Time(u32),
+}
+
+impl Trigger {
+    /// Returns true if the trigger fires, given an event that just happened and the state of the computer before
+    /// and after this step.
+    pub fn fires(&self, event: Option<&Event>, before: &Environment, after: &Environment) -> bool {
+        match self {
+            Trigger::Event(e) => event == Some(e),
+            Trigger::Idle(duration) => before.idle < *duration && after.idle >= *duration,
+            Trigger::Active => after.idle < before.idle,
+            Trigger::Time(time) => {
+                // The steps may skip minutes, for example while the computer sleeps, and the clock may be set back
+                let elapsed = minutes_between(before.time_of_day, after.time_of_day);
+                let target = minutes_between(before.time_of_day, *time);
+                elapsed < MINUTES_PER_DAY / 2 && 0 < target && target <= elapsed
+            }
+        }
+    }
+}
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// The number of minutes from one time of day to the next occurrence of another, across midnight.
+fn minutes_between(from: u32, to: u32) -> u32 {
+    (to + MINUTES_PER_DAY - from) % MINUTES_PER_DAY
+}
+
+/// A trigger that could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TriggerError(String);
+
+impl fmt::Display for TriggerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid trigger {:?}", self.0)
+    }
+}
+
+impl std::error::Error for TriggerError {}
+
+impl FromStr for Trigger {
+    type Err = TriggerError;
+
+    fn from_str(s: &str) -> Result<Trigger, TriggerError> {
+        let invalid = || TriggerError(s.into());
+        let mut words = s.split_whitespace();
+        let trigger = match (words.next(), words.next()) {
+            (Some("display_off"), None) => Trigger::Event(Event::DisplayOff),
+            (Some("display_on"), None) => Trigger::Event(Event::DisplayOn),
+            (Some("display_dimmed"), None) => Trigger::Event(Event::DisplayDimmed),
+            (Some("lock"), None) => Trigger::Event(Event::Lock),
+            (Some("unlock"), None) => Trigger::Event(Event::Unlock),
+            (Some("active"), None) => Trigger::Active,
+            (Some("idle"), Some(seconds)) => match seconds.parse::<f32>() {
+                Ok(seconds) if seconds.is_finite() && seconds > 0.0 => {
+                    Trigger::Idle(Duration::from_secs_f32(seconds))
+                }
+                _ => return Err(invalid()),
+            },
+            (Some("time"), Some(time)) => {
+                let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+                match (hours.parse::<u32>(), minutes.parse::<u32>()) {
+                    (Ok(hours @ 0..=23), Ok(minutes @ 0..=59)) => {
+                        Trigger::Time(hours * 60 + minutes)
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+            (Some("signal"), Some(name)) => Trigger::Event(Event::Signal(name.into())),
+            _ => return Err(invalid()),
+        };
+        match words.next() {
+            Some(_) => Err(invalid()),
+            None => Ok(trigger),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_triggers() {
+        assert_eq!("display_off".parse(), Ok(Trigger::Event(Event::DisplayOff)));
+        assert_eq!(
+            "idle 90".parse(),
+            Ok(Trigger::Idle(Duration::from_secs(90)))
+        );
+        assert_eq!("time 07:30".parse(), Ok(Trigger::Time(7 * 60 + 30)));
+        assert_eq!(
+            "signal party".parse(),
+            Ok(Trigger::Event(Event::Signal("party".into())))
+        );
+        for invalid in [
+            "",
+            "display",
+            "idle",
+            "idle -1",
+            "time 24:00",
+            "time 7",
+            "signal",
+            "lock now",
+        ] {
+            assert!(invalid.parse::<Trigger>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn fire_on_changes_of_the_environment() {
+        let env = |idle: u64, time_of_day: u32| Environment {
+            idle: Duration::from_secs(idle),
+            time_of_day,
+        };
+        let idle: Trigger = "idle 60".parse().unwrap();
+        assert!(idle.fires(None, &env(59, 0), &env(60, 0)));
+        assert!(!idle.fires(None, &env(60, 0), &env(61, 0)));
+        assert!(Trigger::Active.fires(None, &env(60, 0), &env(0, 0)));
+        assert!(!Trigger::Active.fires(None, &env(0, 0), &env(1, 0)));
+
+        let time: Trigger = "time 22:30".parse().unwrap();
+        assert!(time.fires(None, &env(0, 1349), &env(0, 1350)));
+        assert!(!time.fires(None, &env(0, 1350), &env(0, 1350)));
+        assert!(!time.fires(None, &env(0, 1350), &env(0, 1351)));
+        // Steps that skip the time, across midnight or when the clock is set back
+        assert!(time.fires(None, &env(0, 1340), &env(0, 1360)));
+        let midnight: Trigger = "time 00:00".parse().unwrap();
+        assert!(midnight.fires(None, &env(0, 1439), &env(0, 0)));
+        assert!(midnight.fires(None, &env(0, 1430), &env(0, 5)));
+        assert!(!midnight.fires(None, &env(0, 5), &env(0, 10)));
+        assert!(!time.fires(None, &env(0, 1380), &env(0, 1320)));
+
+        let lock = Trigger::Event(Event::Lock);
+        assert!(lock.fires(Some(&Event::Lock), &env(0, 0), &env(0, 0)));
+        assert!(!lock.fires(Some(&Event::Unlock), &env(0, 0), &env(0, 0)));
+    }
+}
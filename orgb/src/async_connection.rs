@@ -0,0 +1,444 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_stream::Stream;
+
+use super::connection::PROTOCOL_VERSION_TIMEOUT;
+use super::protocol::{
+    ControllerData, PacketHeader, PacketKey, Plugin, ProtocolError, Request, Response, Rgb,
+    PROTOCOL_VERSION,
+};
+
+/// How many requests can be queued for the writing task before the request methods wait.
+const WRITE_QUEUE_CAPACITY: usize = 64;
+
+/// How many notifications are kept until the [`Notifications`] stream is polled.
+const NOTIFICATION_CAPACITY: usize = 16;
+
+/// How long the response to a request whose future has been dropped is still expected, since the server may never
+/// send it.
+const ABANDONED_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An asynchronous connection to an OpenRGB server, for use inside a tokio runtime.
+///
+/// The requests are written by a background task, in the order in which they are made. Several requests can wait for
+/// their responses at once: each response goes to the oldest request with the same packet id and device index.
+///
+/// # Cancel safety
+///
+/// Every method is cancel-safe. A request whose future is dropped is either not sent at all, or sent whole and its
+/// response is discarded when it arrives.
+pub struct AsyncConnection {
+    write_tx: mpsc::Sender<Vec<u8>>,
+    pending: Arc<Mutex<Pending>>,
+    protocol_version: Arc<AtomicU32>,
+    client_version: Arc<AtomicU32>,
+}
+
+/// A message that the OpenRGB server sends on its own, outside of any request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notification {
+    /// The list of devices has changed, so the controllers must be requested again.
+    DeviceListUpdated,
+}
+
+/// The stream of notifications sent by the OpenRGB server. It ends when the connection is closed.
+///
+/// Up to 16 notifications are kept until they are read, and the ones that do not fit are dropped: a pending
+/// `DeviceListUpdated` says as much as several of them.
+pub struct Notifications {
+    rx: mpsc::Receiver<Notification>,
+}
+
+impl Stream for Notifications {
+    type Item = Notification;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Notification>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl AsyncConnection {
+    /// Connect to an OpenRGB server and spawn a task that listens to incomming messages.
+    pub async fn connect<A: ToSocketAddrs>(
+        addr: A,
+    ) -> Result<(AsyncConnection, Notifications), ProtocolError> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (write_tx, write_rx) = mpsc::channel(WRITE_QUEUE_CAPACITY);
+        let (notification_tx, notification_rx) = mpsc::channel(NOTIFICATION_CAPACITY);
+        let protocol_version = Arc::new(AtomicU32::new(0));
+        let client_version = Arc::new(AtomicU32::new(PROTOCOL_VERSION));
+
+        let recv_task = tokio::spawn(recv_task(
+            reader,
+            Arc::clone(&pending),
+            notification_tx,
+            Arc::clone(&protocol_version),
+            Arc::clone(&client_version),
+        ));
+        tokio::spawn(write_task(
+            writer,
+            write_rx,
+            Arc::clone(&pending),
+            recv_task,
+        ));
+
+        let connection = AsyncConnection {
+            write_tx,
+            pending,
+            protocol_version,
+            client_version,
+        };
+        let notifications = Notifications {
+            rx: notification_rx,
+        };
+        Ok((connection, notifications))
+    }
+
+    /// Returns the protocol version used to encode and decode packets.
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version.load(Ordering::Relaxed)
+    }
+
+    /// Send a request that has no response.
+    ///
+    /// This returns once the request is queued for writing. If writing fails, the connection is closed and the next
+    /// requests fail.
+    pub async fn send(&self, request: Request<'_>) -> Result<(), ProtocolError> {
+        let bytes = self.encode(request)?;
+        self.queue(bytes, None).await
+    }
+
+    /// Send a request and wait for its response.
+    ///
+    /// The response is the first packet from the server whose id and device index match the request.
+    pub async fn request(&self, request: Request<'_>) -> Result<Response, ProtocolError> {
+        let key = request.response_key().ok_or(ProtocolError::NoResponse)?;
+        let bytes = self.encode(request)?;
+        let (tx, rx) = oneshot::channel();
+        self.queue(bytes, Some((key, tx))).await?;
+        rx.await
+            .unwrap_or_else(|_| Err(ProtocolError::disconnected()))
+    }
+
+    fn encode(&self, request: Request<'_>) -> Result<Vec<u8>, ProtocolError> {
+        if let Request::ProtocolVersion(v) = request {
+            // The receiving task needs it to compute the negotiated version, which must be known to this crate
+            self.client_version
+                .store(v.min(PROTOCOL_VERSION), Ordering::Relaxed);
+        }
+        let mut bytes = Vec::new();
+        request.write_to(&mut bytes, self.protocol_version())?;
+        Ok(bytes)
+    }
+
+    /// Hand the packet over to the writing task, and register the request that waits for the response, if any.
+    async fn queue(
+        &self,
+        bytes: Vec<u8>,
+        waiting: Option<(PacketKey, ResponseSender)>,
+    ) -> Result<(), ProtocolError> {
+        let permit = self
+            .write_tx
+            .reserve()
+            .await
+            .map_err(|_| ProtocolError::disconnected())?;
+        // Nothing can interrupt the rest, and the lock keeps the pending requests in the order in which they are sent
+        let mut pending = self.pending.lock().unwrap();
+        if pending.closed {
+            return Err(ProtocolError::disconnected());
+        }
+        if let Some((key, tx)) = waiting {
+            // Register the request before sending it, so that the response cannot arrive first
+            pending.insert(key, tx);
+        }
+        permit.send(bytes);
+        Ok(())
+    }
+
+    /// Tell the server the name of this client.
+    pub async fn set_client_name(&self, name: &str) -> Result<(), ProtocolError> {
+        self.send(Request::SetClientName(name.into())).await
+    }
+
+    /// Tell the server the highest protocol version supported by this client, and switch to the highest version
+    /// supported by both sides, which is returned.
+    ///
+    /// Until this is called, the connection uses protocol version 0. If the server does not answer within a second,
+    /// it only supports protocol version 0, which is used from then on. Versions above [`PROTOCOL_VERSION`] are
+    /// lowered to it.
+    pub async fn request_protocol_version(&self, version: u32) -> Result<u32, ProtocolError> {
+        let request = self.request(Request::ProtocolVersion(version.min(PROTOCOL_VERSION)));
+        match tokio::time::timeout(PROTOCOL_VERSION_TIMEOUT, request).await {
+            // The receiving task has already switched to the negotiated version
+            Ok(Ok(Response::ProtocolVersion(_))) => Ok(self.protocol_version()),
+            Ok(Ok(other)) => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                log::info!("The server did not answer the protocol version, using version 0");
+                self.protocol_version.store(0, Ordering::Relaxed);
+                Ok(0)
+            }
+        }
+    }
+
+    /// Agree with the OpenRGB server on the highest protocol version supported by both this crate and the server.
+    pub async fn negotiate_protocol_version(&self) -> Result<u32, ProtocolError> {
+        self.request_protocol_version(PROTOCOL_VERSION).await
+    }
+
+    /// Request the number of controllers.
+    pub async fn controller_count(&self) -> Result<u32, ProtocolError> {
+        match self.request(Request::ControllerCount).await? {
+            Response::ControllerCount(c) => Ok(c),
+            other => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+        }
+    }
+
+    /// Request the data of one controller.
+    pub async fn controller_data(
+        &self,
+        controller_idx: u32,
+    ) -> Result<ControllerData, ProtocolError> {
+        match self
+            .request(Request::ControllerData { controller_idx })
+            .await?
+        {
+            Response::ControllerData(c) => Ok(c),
+            other => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+        }
+    }
+
+    /// Request the data of every controller.
+    pub async fn list_controllers(&self) -> Result<Vec<ControllerData>, ProtocolError> {
+        let controller_count = self.controller_count().await?;
+        let mut controllers = Vec::with_capacity(controller_count as usize);
+        for controller_idx in 0..controller_count {
+            controllers.push(self.controller_data(controller_idx).await?);
+        }
+        Ok(controllers)
+    }
+
+    /// Set the colors of all the LEDs of a controller.
+    pub async fn update_leds(
+        &self,
+        controller_idx: u32,
+        colors: &[Rgb],
+    ) -> Result<(), ProtocolError> {
+        self.send(Request::UpdateLeds {
+            controller_idx,
+            colors: colors.into(),
+        })
+        .await
+    }
+
+    /// Request the names of the profiles saved on the server. Requires protocol version 2.
+    pub async fn profile_list(&self) -> Result<Vec<String>, ProtocolError> {
+        match self.request(Request::ProfileList).await? {
+            Response::ProfileList(p) => Ok(p),
+            other => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+        }
+    }
+
+    /// Save the current state of the devices as a profile. Requires protocol version 2.
+    pub async fn save_profile(&self, name: &str) -> Result<(), ProtocolError> {
+        self.send(Request::SaveProfile(name.into())).await
+    }
+
+    /// Apply a saved profile to the devices. Requires protocol version 2.
+    pub async fn load_profile(&self, name: &str) -> Result<(), ProtocolError> {
+        self.send(Request::LoadProfile(name.into())).await
+    }
+
+    /// Delete a saved profile. Requires protocol version 2.
+    pub async fn delete_profile(&self, name: &str) -> Result<(), ProtocolError> {
+        self.send(Request::DeleteProfile(name.into())).await
+    }
+
+    /// Request the plugins loaded by the server. Requires protocol version 4.
+    pub async fn plugin_list(&self) -> Result<Vec<Plugin>, ProtocolError> {
+        match self.request(Request::PluginList).await? {
+            Response::PluginList(p) => Ok(p),
+            other => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+        }
+    }
+
+    /// Send a plugin-specific request and wait for the answer of the plugin. Requires protocol version 4.
+    ///
+    /// Use [`AsyncConnection::send`] for the requests that the plugin does not answer.
+    pub async fn plugin_request(
+        &self,
+        plugin_idx: u32,
+        pkt_type: u32,
+        data: &[u8],
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let request = Request::PluginSpecific {
+            plugin_idx,
+            pkt_type,
+            data: data.into(),
+        };
+        match self.request(request).await? {
+            Response::PluginSpecific { data, .. } => Ok(data),
+            other => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+        }
+    }
+}
+
+type ResponseSender = oneshot::Sender<Result<Response, ProtocolError>>;
+
+/// The requests that are waiting for a response, in the order in which they were sent for each key.
+///
+/// A request whose future has been dropped stays pending for a while, so that its response is not taken by a newer
+/// request with the same key. It is forgotten early if an older dropped request took a response with its key, which
+/// may have been its own: otherwise, requests that the server never answers would each take the response to the next
+/// one.
+#[derive(Default)]
+struct Pending {
+    requests: HashMap<PacketKey, VecDeque<PendingRequest>>,
+    /// Set once the connection is closed, since no response can arrive anymore.
+    closed: bool,
+}
+
+struct PendingRequest {
+    tx: ResponseSender,
+    sent: Instant,
+    shadowed: bool,
+}
+
+impl PendingRequest {
+    /// Returns true if the future of the request has been dropped and its response is still expected.
+    fn is_abandoned(&self) -> bool {
+        self.tx.is_closed() && !self.shadowed && self.sent.elapsed() < ABANDONED_REQUEST_TIMEOUT
+    }
+}
+
+impl Pending {
+    fn insert(&mut self, key: PacketKey, tx: ResponseSender) {
+        let request = PendingRequest {
+            tx,
+            sent: Instant::now(),
+            shadowed: false,
+        };
+        self.requests.entry(key).or_default().push_back(request);
+    }
+
+    /// Take the oldest request that the packet with this key answers.
+    fn take(&mut self, key: PacketKey) -> Option<ResponseSender> {
+        let requests = self.requests.get_mut(&key)?;
+        while requests
+            .front()
+            .is_some_and(|r| r.tx.is_closed() && !r.is_abandoned())
+        {
+            requests.pop_front();
+        }
+        let request = requests.pop_front();
+        if request.as_ref().is_some_and(PendingRequest::is_abandoned) {
+            for r in requests.iter_mut() {
+                r.shadowed = true;
+            }
+        }
+        let request = request.map(|r| r.tx);
+        if requests.is_empty() {
+            self.requests.remove(&key);
+        }
+        request
+    }
+
+    /// Fail the requests that are waiting, and the ones to come.
+    fn close(&mut self) {
+        self.closed = true;
+        self.requests.clear();
+    }
+}
+
+/// Write the queued packets until the connection is dropped or the link is lost, then stop receiving.
+async fn write_task(
+    mut writer: OwnedWriteHalf,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    pending: Arc<Mutex<Pending>>,
+    recv_task: JoinHandle<()>,
+) {
+    while let Some(bytes) = rx.recv().await {
+        if let Err(e) = writer.write_all(&bytes).await {
+            log::warn!("Could not write to the TcpStream: {e}");
+            break;
+        }
+    }
+    recv_task.abort();
+    pending.lock().unwrap().close();
+}
+
+/// Read one packet, and return its key along with the result of parsing its data.
+///
+/// The outer error means that the reader is out of sync, while the inner error is about this packet only.
+async fn read_packet(
+    reader: &mut OwnedReadHalf,
+    version: u32,
+) -> Result<(PacketKey, Result<Response, ProtocolError>), ProtocolError> {
+    let mut header_bytes = [0u8; PacketHeader::SIZE];
+    reader.read_exact(&mut header_bytes).await?;
+    let header = PacketHeader::decode(&header_bytes)?;
+    let key = header.key();
+
+    let mut data_bytes = vec![0u8; header.pkt_size as usize];
+    reader.read_exact(&mut data_bytes).await?;
+    Ok((key, Response::decode(header, version, &data_bytes)))
+}
+
+/// Receive messages from the OpenRGB server until the connection is closed.
+async fn recv_task(
+    mut reader: OwnedReadHalf,
+    pending: Arc<Mutex<Pending>>,
+    notification_tx: mpsc::Sender<Notification>,
+    protocol_version: Arc<AtomicU32>,
+    client_version: Arc<AtomicU32>,
+) {
+    loop {
+        let version = protocol_version.load(Ordering::Relaxed);
+        let (key, result) = match read_packet(&mut reader, version).await {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::warn!("Could not read from the TcpStream: {e}");
+                pending.lock().unwrap().close();
+                return;
+            }
+        };
+        match result {
+            Ok(Response::DeviceListUpdated) => {
+                log::info!("Device list has been updated");
+                // When the stream is full, it already holds one
+                let _ = notification_tx.try_send(Notification::DeviceListUpdated);
+                continue;
+            }
+            Ok(Response::ProtocolVersion(v)) => {
+                // Switch before reading the next packet, which may already use the new layout
+                let client_version = client_version.load(Ordering::Relaxed);
+                protocol_version.store(v.min(client_version), Ordering::Relaxed);
+            }
+            // The whole packet has been consumed even if it is malformed, so the stream is still in sync
+            _ => {}
+        }
+
+        // Hand the packet over to the request that it answers
+        let request = pending.lock().unwrap().take(key);
+        match (request, result) {
+            (Some(tx), result) => {
+                // The request may have been dropped in the meantime
+                let _ = tx.send(result);
+            }
+            (None, Ok(response)) => log::warn!("Ignoring a response to no request: {response:?}"),
+            (None, Err(e)) => log::warn!("Skipping a malformed packet: {e}"),
+        }
+    }
+}
@@ -1,80 +1,561 @@
-use std::net::{TcpStream, ToSocketAddrs};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{self, Receiver};
-use std::sync::Arc;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use super::protocol::{Request, Response};
+use super::protocol::{
+    ControllerData, PacketKey, Plugin, ProtocolError, Request, Response, Rgb, PROTOCOL_VERSION,
+};
 
 /// A wrapper around a TCP connection to an OpenRGB server.
 pub struct Connection {
-    con: TcpStream,
-    rx: Receiver<Response>,
+    con: Arc<Mutex<TcpStream>>,
+    pending: Arc<Mutex<Pending>>,
     devices_updated: Arc<AtomicBool>,
+    protocol_version: Arc<AtomicU32>,
+    client_version: Arc<AtomicU32>,
+    connected: Arc<AtomicBool>,
+    state_rx: Receiver<ConnectionState>,
+    timeouts: Timeouts,
 }
 
-const NUM_CONNECTION_TRIES: i32 = 10;
+/// The state of the link to the OpenRGB server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The link has been lost and the connection is trying to connect again.
+    Disconnected,
+    /// The link has been established again, and the handshake has been performed.
+    Connected,
+}
 
-impl Connection {
-    /// Connect to an OpenRGB server and starts a thread that listens to incomming messages.
+/// How often a pending request checks whether the link has been lost.
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait for the answer to the protocol version. Servers at protocol version 0 do not know the request and
+/// never answer it, so the connection falls back to version 0 after this delay.
+pub(crate) const PROTOCOL_VERSION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The address of an OpenRGB server that runs on this machine with the default settings.
+const DEFAULT_ADDRESS: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6742));
+
+/// Configures how a [`Connection`] connects to the OpenRGB server and how long it waits for it.
+///
+/// The connection attempts are spaced out by a delay that starts at the initial backoff and doubles after each
+/// failure, up to the maximum backoff. Every timeout is disabled by default, except the request timeout which is 5
+/// seconds.
+#[derive(Debug, Clone)]
+pub struct ConnectionBuilder {
+    addrs: Vec<SocketAddr>,
+    client_name: Option<String>,
+    protocol_version: u32,
+    reconnect: bool,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    /// `None` to try forever.
+    tries: Option<u32>,
+    backoff_min: Duration,
+    backoff_max: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Timeouts {
+    connect: Option<Duration>,
+    read: Option<Duration>,
+    write: Option<Duration>,
+    request: Duration,
+}
+
+impl Default for ConnectionBuilder {
+    fn default() -> Self {
+        ConnectionBuilder {
+            addrs: vec![DEFAULT_ADDRESS],
+            client_name: None,
+            protocol_version: PROTOCOL_VERSION,
+            reconnect: false,
+            retry: RetryPolicy {
+                tries: Some(10),
+                backoff_min: Duration::from_secs(1),
+                backoff_max: Duration::from_secs(30),
+            },
+            timeouts: Timeouts {
+                connect: None,
+                read: None,
+                write: None,
+                request: Duration::from_secs(5),
+            },
+        }
+    }
+}
+
+impl ConnectionBuilder {
+    pub fn new() -> ConnectionBuilder {
+        ConnectionBuilder::default()
+    }
+
+    /// The address of the OpenRGB server. Defaults to `127.0.0.1:6742`.
     ///
-    /// If the server is not immediately available, it will attempt to connect 10 times before panicking.
-    pub fn start<A: ToSocketAddrs>(addr: A) -> Connection {
-        // Connect to the server
-        log::info!("Connecting to OpenRGB server...");
+    /// The address is resolved right away, and an error is returned if it cannot be.
+    pub fn address(mut self, address: impl ToSocketAddrs) -> io::Result<ConnectionBuilder> {
+        self.addrs = address.to_socket_addrs()?.collect();
+        if self.addrs.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "no address to connect to",
+            ));
+        }
+        Ok(self)
+    }
+
+    /// The name under which this client appears in the OpenRGB window.
+    pub fn client_name(mut self, name: &str) -> ConnectionBuilder {
+        self.client_name = Some(name.into());
+        self
+    }
+
+    /// The highest protocol version to ask the server for. Defaults to [`PROTOCOL_VERSION`].
+    pub fn protocol_version(mut self, version: u32) -> ConnectionBuilder {
+        self.protocol_version = version.min(PROTOCOL_VERSION);
+        self
+    }
+
+    /// Whether to connect again, with the same handshake, whenever the link is lost. Defaults to false.
+    ///
+    /// After a reconnection, the devices updated flag is raised so that the controllers are requested again.
+    pub fn reconnect(mut self, reconnect: bool) -> ConnectionBuilder {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// How many times to attempt to connect before giving up. Defaults to 10.
+    ///
+    /// This only applies to the first connection: reconnections are attempted forever.
+    pub fn connection_tries(mut self, tries: u32) -> ConnectionBuilder {
+        self.retry.tries = Some(tries.max(1));
+        self
+    }
+
+    /// Attempt to connect until it succeeds.
+    pub fn retry_forever(mut self) -> ConnectionBuilder {
+        self.retry.tries = None;
+        self
+    }
+
+    /// The delay before the second connection attempt, which doubles after each failure up to `max`. Defaults to
+    /// 1 and 30 seconds. Pass the same value twice for evenly spaced attempts.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> ConnectionBuilder {
+        self.retry.backoff_min = initial;
+        self.retry.backoff_max = max.max(initial);
+        self
+    }
+
+    /// How long a single connection attempt may take.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timeout is zero.
+    pub fn connect_timeout(mut self, timeout: Duration) -> ConnectionBuilder {
+        assert!(!timeout.is_zero(), "The connect timeout must not be zero");
+        self.timeouts.connect = Some(timeout);
+        self
+    }
+
+    /// How long the server may stall in the middle of a packet before the link is considered lost.
+    ///
+    /// The server is allowed to stay silent for any amount of time between two packets.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timeout is zero.
+    pub fn read_timeout(mut self, timeout: Duration) -> ConnectionBuilder {
+        assert!(!timeout.is_zero(), "The read timeout must not be zero");
+        self.timeouts.read = Some(timeout);
+        self
+    }
+
+    /// How long sending a request may block before the link is considered lost.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timeout is zero.
+    pub fn write_timeout(mut self, timeout: Duration) -> ConnectionBuilder {
+        assert!(!timeout.is_zero(), "The write timeout must not be zero");
+        self.timeouts.write = Some(timeout);
+        self
+    }
+
+    /// How long [`Connection::request`] waits for the response. This also bounds the handshake, after which the server
+    /// is assumed to only support protocol version 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timeout is zero.
+    pub fn request_timeout(mut self, timeout: Duration) -> ConnectionBuilder {
+        assert!(!timeout.is_zero(), "The request timeout must not be zero");
+        self.timeouts.request = timeout;
+        self
+    }
+
+    /// Connect to the OpenRGB server, perform the handshake and start a thread that listens to incomming messages.
+    ///
+    /// The handshake sends the client name, if any, and agrees with the server on a protocol version, which is
+    /// returned along with the connection. Returns the error of the last attempt if none of them succeeds.
+    pub fn connect(self) -> Result<(Connection, u32), ProtocolError> {
+        log::info!("Connecting to OpenRGB server at {}...", self.addrs[0]);
+        let handshake = Handshake {
+            addrs: self.addrs,
+            client_name: self.client_name,
+            retry: self.retry,
+            timeouts: self.timeouts,
+        };
+        let (con, version) = self
+            .retry
+            .retry(|| handshake.connect(self.protocol_version))?;
+
+        let reconnect = self.reconnect.then_some(handshake);
+        let connection = Connection::spawn(
+            con,
+            version,
+            self.protocol_version,
+            reconnect,
+            self.timeouts,
+        );
+        Ok((connection, version))
+    }
+}
+
+impl RetryPolicy {
+    /// Call the function until it succeeds or the tries are exhausted, sleeping between the attempts.
+    fn retry<T>(
+        &self,
+        mut attempt: impl FnMut() -> Result<T, ProtocolError>,
+    ) -> Result<T, ProtocolError> {
+        let mut backoff = self.backoff_min;
         let mut num_tries = 0;
-        let con = loop {
-            match TcpStream::connect(&addr) {
-                Ok(con) => break con,
-                Err(_) => {
+        loop {
+            match attempt() {
+                Ok(x) => return Ok(x),
+                Err(e) => {
                     num_tries += 1;
-                    if num_tries >= NUM_CONNECTION_TRIES {
-                        panic!("Could not connect, aborting");
-                    } else {
-                        log::info!("Could not connect, retrying...");
+                    if self.tries.is_some_and(|tries| num_tries >= tries) {
+                        return Err(e);
                     }
-                    thread::sleep(Duration::from_secs(1));
+                    log::info!("Could not connect ({e}), retrying in {backoff:?}...");
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.backoff_max);
                 }
             }
-        };
+        }
+    }
+}
+
+/// Open a TCP connection to the first address that accepts it, and apply the socket timeouts.
+fn connect(addrs: &[SocketAddr], timeouts: &Timeouts) -> io::Result<TcpStream> {
+    let con = match timeouts.connect {
+        Some(timeout) => {
+            let mut last_error = None;
+            let con = addrs.iter().find_map(|addr| {
+                TcpStream::connect_timeout(addr, timeout)
+                    .map_err(|e| last_error = Some(e))
+                    .ok()
+            });
+            match con {
+                Some(con) => con,
+                None => {
+                    return Err(last_error.unwrap_or_else(|| {
+                        io::Error::new(ErrorKind::InvalidInput, "no address to connect to")
+                    }))
+                }
+            }
+        }
+        None => TcpStream::connect(addrs)?,
+    };
+    con.set_read_timeout(timeouts.read)?;
+    con.set_write_timeout(timeouts.write)?;
+    Ok(con)
+}
+
+impl Connection {
+    /// Returns a builder to configure the address, the handshake, the connection attempts and the timeouts.
+    pub fn builder() -> ConnectionBuilder {
+        ConnectionBuilder::new()
+    }
+
+    /// Connect to an OpenRGB server and starts a thread that listens to incomming messages, without any handshake.
+    ///
+    /// If the server is not immediately available, it will attempt to connect 10 times, one second apart, before
+    /// panicking. Use [`Connection::builder`] to change that.
+    pub fn start<A: ToSocketAddrs>(addr: A) -> Connection {
+        let builder =
+            ConnectionBuilder::new().backoff(Duration::from_secs(1), Duration::from_secs(1));
+        let addrs: Vec<SocketAddr> = addr
+            .to_socket_addrs()
+            .expect("Could not resolve the address")
+            .collect();
 
-        // A channel to receive responses and a flag to indicate device updates
-        let (tx, rx) = mpsc::sync_channel(0);
+        log::info!("Connecting to OpenRGB server...");
+        let con = builder
+            .retry
+            .retry(|| Ok(connect(&addrs, &builder.timeouts)?))
+            .unwrap_or_else(|e| panic!("Could not connect, aborting: {e}"));
+
+        Connection::spawn(con, 0, PROTOCOL_VERSION, None, builder.timeouts)
+    }
+
+    fn spawn(
+        con: TcpStream,
+        version: u32,
+        client_version: u32,
+        handshake: Option<Handshake>,
+        timeouts: Timeouts,
+    ) -> Connection {
+        // The requests waiting for a response and a flag to indicate device updates
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (state_tx, state_rx) = mpsc::channel();
         let devices_updated = Arc::new(AtomicBool::new(true));
+        let protocol_version = Arc::new(AtomicU32::new(version));
+        let client_version = Arc::new(AtomicU32::new(client_version));
+        let connected = Arc::new(AtomicBool::new(true));
+        let reader = con.try_clone().expect("Could not clone the TcpStream");
+        let con = Arc::new(Mutex::new(con));
 
         // Launch the thread that receives messages from the OpenRGB server
         let _recv_thread = {
-            let devices_updated = Arc::clone(&devices_updated);
-            let mut con = con.try_clone().expect("Could not clone the TcpStream");
-            thread::spawn(move || loop {
-                match Response::read_from(&mut con).expect("Could not read from the TcpStream") {
-                    Response::DeviceListUpdated => {
-                        log::info!("Device list has been updated");
-                        devices_updated.store(true, Ordering::Relaxed)
-                    }
-                    other => tx.send(other).expect("Receiver has been destroyed"),
-                }
-            })
+            let receiver = RecvThread {
+                pending: Arc::clone(&pending),
+                state_tx,
+                con: Arc::clone(&con),
+                devices_updated: Arc::clone(&devices_updated),
+                protocol_version: Arc::clone(&protocol_version),
+                client_version: Arc::clone(&client_version),
+                connected: Arc::clone(&connected),
+                handshake,
+            };
+            thread::spawn(move || receiver.run(reader))
         };
 
         Connection {
             con,
-            rx,
+            pending,
             devices_updated,
+            protocol_version,
+            client_version,
+            connected,
+            state_rx,
+            timeouts,
         }
     }
 
     /// Send a request to the OpenRGB server.
-    pub fn send(&mut self, request: Request) {
-        request
-            .write_to(&mut self.con)
-            .expect("Could not write to the TcpStream");
+    ///
+    /// On a reconnecting connection, requests that fail while the link is down are not sent again once it is back.
+    pub fn send(&mut self, request: Request) -> Result<(), ProtocolError> {
+        if let Request::ProtocolVersion(v) = request {
+            // The receiving thread needs it to compute the negotiated version, which must be known to this crate
+            self.client_version
+                .store(v.min(PROTOCOL_VERSION), Ordering::Relaxed);
+        }
+        let version = self.protocol_version();
+        let mut con = self.con.lock().unwrap();
+        request.write_to(&mut *con, version).map_err(|e| {
+            // Make sure that the receiving thread notices the loss
+            let _ = con.shutdown(Shutdown::Both);
+            e.into()
+        })
+    }
+
+    /// Send a request and wait for its response, for at most the request timeout.
+    ///
+    /// The response is the first packet from the server whose id and device index match the request. Packets that
+    /// arrive after the request timed out are ignored.
+    pub fn request(&mut self, request: Request) -> Result<Response, ProtocolError> {
+        self.request_timeout(request, self.timeouts.request)
+    }
+
+    /// Send a request and wait for its response, for at most the given time instead of the request timeout.
+    pub fn request_timeout(
+        &mut self,
+        request: Request,
+        timeout: Duration,
+    ) -> Result<Response, ProtocolError> {
+        let key = request.response_key().ok_or(ProtocolError::NoResponse)?;
+        if !self.is_connected() {
+            return Err(ProtocolError::disconnected());
+        }
+        // Register the request before sending it, so that the response cannot arrive first
+        let (tx, rx) = mpsc::channel();
+        let id = self.pending.lock().unwrap().insert(key, tx);
+        if let Err(e) = self.send(request) {
+            self.pending.lock().unwrap().remove(id);
+            return Err(e);
+        }
+        let result = self.wait(&rx, timeout);
+        let mut pending = self.pending.lock().unwrap();
+        match result {
+            Err(ProtocolError::Timeout) => pending.abandon(id, self.timeouts.request),
+            _ => pending.remove(id),
+        }
+        drop(pending);
+        result
+    }
+
+    /// Wait for the response sent through the given receiver by the receiving thread.
+    fn wait(
+        &self,
+        rx: &Receiver<Result<Response, ProtocolError>>,
+        timeout: Duration,
+    ) -> Result<Response, ProtocolError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if !self.is_connected() {
+                return Err(ProtocolError::disconnected());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ProtocolError::Timeout);
+            }
+            match rx.recv_timeout(RECV_POLL_INTERVAL.min(deadline - now)) {
+                Ok(result) => return result,
+                Err(RecvTimeoutError::Timeout) => continue,
+                // The pending requests are dropped when the link is lost
+                Err(RecvTimeoutError::Disconnected) => return Err(ProtocolError::disconnected()),
+            }
+        }
     }
 
-    /// Wait for a response from the OpenRGB server.
-    pub fn recv(&self) -> Response {
-        self.rx.recv().expect("Sender has been destroyed")
+    /// Tell the server the name of this client.
+    pub fn set_client_name(&mut self, name: &str) -> Result<(), ProtocolError> {
+        self.send(Request::SetClientName(name.into()))
+    }
+
+    /// Tell the server the highest protocol version supported by this client, and switch to the highest version
+    /// supported by both sides, which is returned.
+    ///
+    /// Until this is called, the connection uses protocol version 0. If the server does not answer within a second,
+    /// it only supports protocol version 0, which is used from then on. Versions above [`PROTOCOL_VERSION`] are
+    /// lowered to it.
+    pub fn request_protocol_version(&mut self, version: u32) -> Result<u32, ProtocolError> {
+        let timeout = self.timeouts.request.min(PROTOCOL_VERSION_TIMEOUT);
+        let request = Request::ProtocolVersion(version.min(PROTOCOL_VERSION));
+        match self.request_timeout(request, timeout) {
+            // The receiving thread has already switched to the negotiated version
+            Ok(Response::ProtocolVersion(_)) => Ok(self.protocol_version()),
+            Ok(other) => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+            Err(ProtocolError::Timeout) => {
+                log::info!("The server did not answer the protocol version, using version 0");
+                self.protocol_version.store(0, Ordering::Relaxed);
+                Ok(0)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Agree with the OpenRGB server on the highest protocol version supported by both this crate and the server.
+    pub fn negotiate_protocol_version(&mut self) -> Result<u32, ProtocolError> {
+        self.request_protocol_version(PROTOCOL_VERSION)
+    }
+
+    /// Request the number of controllers.
+    pub fn controller_count(&mut self) -> Result<u32, ProtocolError> {
+        match self.request(Request::ControllerCount)? {
+            Response::ControllerCount(c) => Ok(c),
+            other => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+        }
+    }
+
+    /// Request the data of one controller.
+    pub fn controller_data(
+        &mut self,
+        controller_idx: u32,
+    ) -> Result<ControllerData, ProtocolError> {
+        match self.request(Request::ControllerData { controller_idx })? {
+            Response::ControllerData(c) => Ok(c),
+            other => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+        }
+    }
+
+    /// Request the data of every controller.
+    pub fn list_controllers(&mut self) -> Result<Vec<ControllerData>, ProtocolError> {
+        let controller_count = self.controller_count()?;
+        let mut controllers = Vec::with_capacity(controller_count as usize);
+        for controller_idx in 0..controller_count {
+            controllers.push(self.controller_data(controller_idx)?);
+        }
+        Ok(controllers)
+    }
+
+    /// Set the colors of all the LEDs of a controller.
+    pub fn update_leds(
+        &mut self,
+        controller_idx: u32,
+        colors: &[Rgb],
+    ) -> Result<(), ProtocolError> {
+        self.send(Request::UpdateLeds {
+            controller_idx,
+            colors: colors.into(),
+        })
+    }
+
+    /// Request the names of the profiles saved on the server. Requires protocol version 2.
+    pub fn profile_list(&mut self) -> Result<Vec<String>, ProtocolError> {
+        match self.request(Request::ProfileList)? {
+            Response::ProfileList(p) => Ok(p),
+            other => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+        }
+    }
+
+    /// Save the current state of the devices as a profile. Requires protocol version 2.
+    pub fn save_profile(&mut self, name: &str) -> Result<(), ProtocolError> {
+        self.send(Request::SaveProfile(name.into()))
+    }
+
+    /// Apply a saved profile to the devices. Requires protocol version 2.
+    pub fn load_profile(&mut self, name: &str) -> Result<(), ProtocolError> {
+        self.send(Request::LoadProfile(name.into()))
+    }
+
+    /// Delete a saved profile. Requires protocol version 2.
+    pub fn delete_profile(&mut self, name: &str) -> Result<(), ProtocolError> {
+        self.send(Request::DeleteProfile(name.into()))
+    }
+
+    /// Request the plugins loaded by the server. Requires protocol version 4.
+    pub fn plugin_list(&mut self) -> Result<Vec<Plugin>, ProtocolError> {
+        match self.request(Request::PluginList)? {
+            Response::PluginList(p) => Ok(p),
+            other => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+        }
+    }
+
+    /// Send a plugin-specific request and wait for the answer of the plugin. Requires protocol version 4.
+    ///
+    /// Use [`Connection::send`] for the requests that the plugin does not answer.
+    pub fn plugin_request(
+        &mut self,
+        plugin_idx: u32,
+        pkt_type: u32,
+        data: &[u8],
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let request = Request::PluginSpecific {
+            plugin_idx,
+            pkt_type,
+            data: data.into(),
+        };
+        match self.request(request)? {
+            Response::PluginSpecific { data, .. } => Ok(data),
+            other => Err(ProtocolError::UnexpectedResponse(Box::new(other))),
+        }
+    }
+
+    /// Returns the protocol version used to encode and decode packets.
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version.load(Ordering::Relaxed)
     }
 
     /// Returns the flag that indicates when the list of devices has been updated, then resets the flag.
@@ -83,4 +564,242 @@ impl Connection {
     pub fn devices_updated_reset(&self) -> bool {
         self.devices_updated.swap(false, Ordering::Relaxed)
     }
+
+    /// Returns true if the link to the OpenRGB server is currently up.
+    ///
+    /// Without reconnection, the connection stays down once the link is lost, and every request fails.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Returns the changes of connection state that happened since the last call.
+    pub fn state_changes(&self) -> impl Iterator<Item = ConnectionState> + '_ {
+        self.state_rx.try_iter()
+    }
+}
+
+/// The requests that are waiting for a response, in the order in which they were sent.
+#[derive(Default)]
+struct Pending {
+    next_id: u64,
+    requests: Vec<PendingRequest>,
+}
+
+struct PendingRequest {
+    id: u64,
+    key: PacketKey,
+    tx: Sender<Result<Response, ProtocolError>>,
+    /// Once the request has timed out, the time until which its late response is still expected.
+    expires: Option<Instant>,
+    /// Set when an older request that had timed out took a response with the same key, which may have been the
+    /// response to this one.
+    shadowed: bool,
+}
+
+impl Pending {
+    fn insert(&mut self, key: PacketKey, tx: Sender<Result<Response, ProtocolError>>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.requests.push(PendingRequest {
+            id,
+            key,
+            tx,
+            expires: None,
+            shadowed: false,
+        });
+        id
+    }
+
+    /// Keep a request that timed out for a while, so that its late response is not taken by a newer request with
+    /// the same key.
+    fn abandon(&mut self, id: u64, grace: Duration) {
+        let Some(idx) = self.requests.iter().position(|r| r.id == id) else {
+            return;
+        };
+        // Otherwise, requests that the server never answers would each take the response to the next one
+        if self.requests[idx].shadowed {
+            self.requests.remove(idx);
+        } else {
+            self.requests[idx].expires = Some(Instant::now() + grace);
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.requests.retain(|r| r.id != id);
+    }
+
+    /// Take the oldest request that the packet with this key answers.
+    fn take(&mut self, key: PacketKey) -> Option<PendingRequest> {
+        // The server may never answer some requests, so their responses stop being expected at some point
+        let now = Instant::now();
+        self.requests.retain(|r| r.expires.is_none_or(|e| e > now));
+        let idx = self.requests.iter().position(|r| r.key == key)?;
+        let request = self.requests.remove(idx);
+        if request.expires.is_some() {
+            for r in self.requests.iter_mut().filter(|r| r.key == key) {
+                r.shadowed = true;
+            }
+        }
+        Some(request)
+    }
+}
+
+/// Everything that is needed to establish a connection again.
+struct Handshake {
+    addrs: Vec<SocketAddr>,
+    client_name: Option<String>,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+}
+
+impl Handshake {
+    /// Connect to the server, retrying forever, and return the stream along with the negotiated protocol version.
+    fn connect_forever(&self, client_version: u32) -> (TcpStream, u32) {
+        let retry = RetryPolicy {
+            tries: None,
+            ..self.retry
+        };
+        retry
+            .retry(|| self.connect(client_version))
+            .expect("Retrying forever cannot fail")
+    }
+
+    /// Connect to the server once and return the stream along with the negotiated protocol version.
+    fn connect(&self, client_version: u32) -> Result<(TcpStream, u32), ProtocolError> {
+        let mut con = connect(&self.addrs, &self.timeouts)?;
+        if let Some(name) = &self.client_name {
+            Request::SetClientName(name.as_str().into()).write_to(&mut con, 0)?;
+        }
+        Request::ProtocolVersion(client_version).write_to(&mut con, 0)?;
+
+        // The answer is a response like any other, so it gets the request timeout. Servers at protocol version 0 do
+        // not know the request and never answer it.
+        let deadline = Instant::now() + self.timeouts.request.min(PROTOCOL_VERSION_TIMEOUT);
+        let version = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break None;
+            }
+            con.set_read_timeout(Some(remaining))?;
+            let response = match Response::read_from(&mut con, 0) {
+                Err(ProtocolError::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    break None;
+                }
+                result => result?,
+            };
+            match response {
+                Response::ProtocolVersion(v) => break Some(v.min(client_version)),
+                other => log::debug!("Ignoring a packet received during the handshake: {other:?}"),
+            }
+        };
+        let version = version.unwrap_or_else(|| {
+            log::info!("The server did not answer the protocol version, using version 0");
+            0
+        });
+        con.set_read_timeout(self.timeouts.read)?;
+        Ok((con, version))
+    }
+}
+
+/// The state owned by the thread that receives messages from the OpenRGB server.
+struct RecvThread {
+    pending: Arc<Mutex<Pending>>,
+    state_tx: mpsc::Sender<ConnectionState>,
+    con: Arc<Mutex<TcpStream>>,
+    devices_updated: Arc<AtomicBool>,
+    protocol_version: Arc<AtomicU32>,
+    client_version: Arc<AtomicU32>,
+    connected: Arc<AtomicBool>,
+    handshake: Option<Handshake>,
+}
+
+impl RecvThread {
+    fn run(self, mut reader: TcpStream) {
+        loop {
+            wait_for_packet(&reader);
+            let version = self.protocol_version.load(Ordering::Relaxed);
+            match Response::read_packet(&mut reader, version) {
+                Ok((_, Ok(Response::DeviceListUpdated))) => {
+                    log::info!("Device list has been updated");
+                    self.devices_updated.store(true, Ordering::Relaxed)
+                }
+                Ok((key, Ok(Response::ProtocolVersion(v)))) => {
+                    // Switch before reading the next packet, which may already use the new layout
+                    let client_version = self.client_version.load(Ordering::Relaxed);
+                    self.protocol_version
+                        .store(v.min(client_version), Ordering::Relaxed);
+                    self.dispatch(key, Ok(Response::ProtocolVersion(v)))
+                }
+                // The whole packet has been consumed even if it is malformed, so the stream is still in sync
+                Ok((key, result)) => self.dispatch(key, result),
+                Err(e) => match &self.handshake {
+                    Some(handshake) => {
+                        self.fail_pending();
+                        reader = self.reconnect(handshake, e);
+                    }
+                    None => {
+                        log::error!("Lost the connection to the OpenRGB server: {e}");
+                        self.connected.store(false, Ordering::Relaxed);
+                        self.fail_pending();
+                        return;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Fail the requests that are waiting, since their responses will never arrive.
+    fn fail_pending(&self) {
+        for request in self.pending.lock().unwrap().requests.drain(..) {
+            let _ = request.tx.send(Err(ProtocolError::disconnected()));
+        }
+    }
+
+    /// Hand the packet over to the request that it answers.
+    fn dispatch(&self, key: PacketKey, result: Result<Response, ProtocolError>) {
+        match (self.pending.lock().unwrap().take(key), result) {
+            (Some(request), result) => {
+                // The request may have given up in the meantime
+                let _ = request.tx.send(result);
+            }
+            (None, Ok(response)) => log::warn!("Ignoring a response to no request: {response:?}"),
+            (None, Err(e)) => log::warn!("Skipping a malformed packet: {e}"),
+        }
+    }
+
+    /// Establish the connection again and return the new stream to read from.
+    fn reconnect(&self, handshake: &Handshake, error: ProtocolError) -> TcpStream {
+        log::warn!("Lost the connection to the OpenRGB server: {error}");
+        self.connected.store(false, Ordering::Relaxed);
+        let _ = self.state_tx.send(ConnectionState::Disconnected);
+        let _ = self.con.lock().unwrap().shutdown(Shutdown::Both);
+
+        // Give the server some time to come back
+        thread::sleep(handshake.retry.backoff_min);
+        let client_version = self.client_version.load(Ordering::Relaxed);
+        let (con, version) = handshake.connect_forever(client_version);
+        let reader = con.try_clone().expect("Could not clone the TcpStream");
+        *self.con.lock().unwrap() = con;
+        self.protocol_version.store(version, Ordering::Relaxed);
+
+        log::info!("Reconnected to the OpenRGB server with protocol version {version}");
+        self.devices_updated.store(true, Ordering::Relaxed);
+        self.connected.store(true, Ordering::Relaxed);
+        let _ = self.state_tx.send(ConnectionState::Connected);
+        reader
+    }
+}
+
+/// Block until the next packet starts arriving, since the server may stay silent for any amount of time between
+/// two packets. The read timeout only applies once a packet has started.
+fn wait_for_packet(reader: &TcpStream) {
+    loop {
+        match reader.peek(&mut [0u8]) {
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            // Other errors will show up again when reading the packet
+            _ => return,
+        }
+    }
 }
@@ -1,9 +1,27 @@
 //! 💡 Talk with an OpenRGB server 💡
 //!
+//! The blocking [`Connection`] is always available. The `tokio` feature adds an [`AsyncConnection`] for use inside
+//! a tokio runtime. The [`Server`] publishes software-defined [`VirtualDevice`]s to OpenRGB clients. The `testing`
+//! feature adds a [`MockServer`] to test clients without an OpenRGB server.
+//!
 //! [Network protocol documentation](https://gitlab.com/OpenRGBDevelopers/OpenRGB-Wiki/-/blob/stable/Developer-Documentation/OpenRGB-SDK-Documentation.md)
 
+#[cfg(feature = "tokio")]
+mod async_connection;
 mod connection;
+#[cfg(feature = "testing")]
+mod mock_server;
 mod protocol;
+mod registry;
+mod selector;
+mod server;
 
-pub use connection::Connection;
+#[cfg(feature = "tokio")]
+pub use async_connection::{AsyncConnection, Notification, Notifications};
+pub use connection::{Connection, ConnectionBuilder, ConnectionState};
+#[cfg(feature = "testing")]
+pub use mock_server::{mock_controller, MockServer};
 pub use protocol::*;
+pub use registry::{DeviceDiff, DeviceId, DeviceRegistry};
+pub use selector::{Selection, Selector, SelectorError, SelectorErrorKind};
+pub use server::{Server, VirtualDevice};
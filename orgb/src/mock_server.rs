@@ -0,0 +1,269 @@
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::protocol::{
+    ColorMode, ControllerData, ControllerType, Led, Mode, ModeFlags, ProtocolError, Request,
+    Response, Rgb, Zone, ZoneType, PROTOCOL_VERSION,
+};
+
+/// An OpenRGB server that runs inside the current process, to test clients without real hardware.
+///
+/// The server listens on a random local port, answers the requests with a configurable list of controllers, and
+/// records every request that it receives. It stops when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    request_received: Condvar,
+}
+
+struct State {
+    controllers: Vec<ControllerData>,
+    protocol_version: u32,
+    profiles: Vec<String>,
+    requests: Vec<Request<'static>>,
+    unresponsive: bool,
+    clients: Vec<TcpStream>,
+    shutdown: bool,
+}
+
+impl MockServer {
+    /// Start a server that serves the given controllers.
+    pub fn start(controllers: Vec<ControllerData>) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind the mock server");
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                controllers,
+                protocol_version: PROTOCOL_VERSION,
+                profiles: Vec::new(),
+                requests: Vec::new(),
+                unresponsive: false,
+                clients: Vec::new(),
+                shutdown: false,
+            }),
+            request_received: Condvar::new(),
+        });
+
+        // Launch the thread that accepts clients
+        let _accept_thread = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                for con in listener.incoming() {
+                    let Ok(con) = con else { continue };
+                    let mut state = shared.state.lock().unwrap();
+                    if state.shutdown {
+                        return;
+                    }
+                    state
+                        .clients
+                        .push(con.try_clone().expect("Could not clone the TcpStream"));
+                    let shared = Arc::clone(&shared);
+                    thread::spawn(move || serve_client(con, &shared));
+                }
+            })
+        };
+
+        MockServer { addr, shared }
+    }
+
+    /// The address to connect to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Set the highest protocol version supported by the server. Defaults to [`PROTOCOL_VERSION`].
+    ///
+    /// At version 0, the server does not know the `ProtocolVersion` request and does not answer it, like the
+    /// OpenRGB servers that predate it.
+    pub fn set_protocol_version(&self, version: u32) {
+        self.state().protocol_version = version;
+    }
+
+    /// Stop answering the requests, as a server that hangs would, until called again with `false`. The requests are
+    /// still recorded.
+    pub fn set_unresponsive(&self, unresponsive: bool) {
+        self.state().unresponsive = unresponsive;
+    }
+
+    /// Replace the controllers served to the clients. Call [`MockServer::notify_device_list_updated`] to tell them.
+    pub fn set_controllers(&self, controllers: Vec<ControllerData>) {
+        self.state().controllers = controllers;
+    }
+
+    /// Replace the profiles saved on the server. Saving and deleting profiles through the server also changes them.
+    pub fn set_profiles(&self, profiles: Vec<String>) {
+        self.state().profiles = profiles;
+    }
+
+    /// Returns the profiles saved on the server.
+    pub fn profiles(&self) -> Vec<String> {
+        self.state().profiles.clone()
+    }
+
+    /// Send a `DeviceListUpdated` notification to every client.
+    pub fn notify_device_list_updated(&self) {
+        let mut state = self.state();
+        let version = state.protocol_version;
+        for client in &mut state.clients {
+            let _ = Response::DeviceListUpdated.write_to(client, 0, version);
+        }
+    }
+
+    /// Close the connection with every client, as a server that shuts down would.
+    pub fn disconnect_clients(&self) {
+        for client in self.state().clients.drain(..) {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Returns every request received so far, from all the clients.
+    pub fn requests(&self) -> Vec<Request<'static>> {
+        self.state().requests.clone()
+    }
+
+    /// Returns every request received so far, and forget about them.
+    pub fn take_requests(&self) -> Vec<Request<'static>> {
+        std::mem::take(&mut self.state().requests)
+    }
+
+    /// Wait until at least `count` requests have been received, then take them all.
+    ///
+    /// Panics if they do not arrive within the timeout.
+    pub fn wait_for_requests(&self, count: usize, timeout: Duration) -> Vec<Request<'static>> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state();
+        while state.requests.len() < count {
+            let now = Instant::now();
+            if now >= deadline {
+                panic!(
+                    "Expected {count} requests, received {}: {:?}",
+                    state.requests.len(),
+                    state.requests
+                );
+            }
+            state = self
+                .shared
+                .request_received
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+        std::mem::take(&mut state.requests)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.state().shutdown = true;
+        self.disconnect_clients();
+        // Wake up the accepting thread so that it notices the shutdown
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+/// Answer the requests of one client until it disconnects.
+fn serve_client(mut con: TcpStream, shared: &Shared) {
+    let mut version = 0;
+    loop {
+        let request = match Request::read_from(&mut con, version) {
+            Ok(request) => request,
+            Err(ProtocolError::Io(_) | ProtocolError::BadMagic) => return,
+            Err(e) => {
+                log::warn!("Mock server is skipping a malformed packet: {e}");
+                continue;
+            }
+        };
+
+        let mut state = shared.state.lock().unwrap();
+        let response = match &request {
+            _ if state.unresponsive => None,
+            Request::ControllerCount => {
+                Some((0, Response::ControllerCount(state.controllers.len() as u32)))
+            }
+            &Request::ControllerData { controller_idx } => state
+                .controllers
+                .get(controller_idx as usize)
+                .map(|c| (controller_idx, Response::ControllerData(c.clone()))),
+            Request::ProtocolVersion(_) if state.protocol_version > 0 => {
+                Some((0, Response::ProtocolVersion(state.protocol_version)))
+            }
+            Request::ProfileList => Some((0, Response::ProfileList(state.profiles.clone()))),
+            Request::SaveProfile(name) => {
+                if !state.profiles.iter().any(|p| *p == **name) {
+                    state.profiles.push(name.to_string());
+                }
+                None
+            }
+            Request::DeleteProfile(name) => {
+                state.profiles.retain(|p| *p != **name);
+                None
+            }
+            _ => None,
+        };
+        if let Some((dev_idx, response)) = response {
+            let written = response.write_to(&mut con, dev_idx, version).is_ok();
+            // The client only switches to the negotiated version once it has the answer
+            if let (true, Request::ProtocolVersion(v)) = (written, &request) {
+                version = (*v).min(state.protocol_version);
+            }
+        }
+        state.requests.push(request);
+        shared.request_received.notify_all();
+    }
+}
+
+/// Build a controller with a single linear zone of `num_leds` black LEDs and a single "Direct" mode.
+pub fn mock_controller(ty: ControllerType, name: &str, num_leds: usize) -> ControllerData {
+    ControllerData {
+        ty,
+        name: name.into(),
+        vendor: Some("Mock".into()),
+        description: format!("Mock {name}"),
+        version: "1.0".into(),
+        serial: format!("MOCK-{name}"),
+        location: format!("mock://{name}"),
+        modes: vec![Mode {
+            name: "Direct".into(),
+            value: 0,
+            flags: ModeFlags::PER_LED_SETTINGS,
+            speed_min: 0,
+            speed_max: 0,
+            brightness_min: Some(0),
+            brightness_max: Some(0),
+            colors_min: 0,
+            colors_max: 0,
+            speed: 0,
+            brightness: Some(0),
+            direction: 0,
+            color_mode: ColorMode::PerLed,
+            colors: Vec::new(),
+        }],
+        active_mode: 0,
+        zones: vec![Zone {
+            name: format!("{name} zone"),
+            ty: ZoneType::Linear,
+            leds_min: num_leds as u32,
+            leds_max: num_leds as u32,
+            leds_count: num_leds as u32,
+            matrix: None,
+            segments: Some(Vec::new()),
+        }],
+        leds: (0..num_leds)
+            .map(|i| Led {
+                name: format!("LED {i}"),
+                value: 0,
+            })
+            .collect(),
+        colors: vec![Rgb(0, 0, 0); num_leds],
+    }
+}
@@ -1,9 +1,19 @@
 #![allow(non_upper_case_globals)] // Make rust-analyzer stfu
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use std::borrow::Cow;
+use std::fmt;
 use std::io::{Read, Write};
 
-#[derive(Debug, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq)]
+/// The highest version of the OpenRGB SDK protocol that this crate understands.
+///
+/// - Version 1 adds the vendor string to the controller data.
+/// - Version 2 adds the profile commands.
+/// - Version 3 adds the brightness fields to the modes.
+/// - Version 4 adds the segments to the zones and the plugin commands.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+#[derive(Debug, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq, Hash)]
 #[repr(u32)]
 pub enum ControllerType {
     Motherboard = 0,
@@ -26,14 +36,14 @@ pub enum ZoneType {
     Matrix = 2,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Rgb(pub u8, pub u8, pub u8);
 
 /// The LED structure contains information about an LED.
 ///
 /// The Value has no defined functionality in the RGBController API and is provided for implementation-specific use.
 /// You can use this field to associate implementation-specific data with an LED.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Led {
     pub name: String,
     pub value: u32,
@@ -42,16 +52,27 @@ pub struct Led {
 /// The matrix map is used to provide positioning information about LEDs in a 2D grid.
 /// The values of the map are LED index values in the zone (so offset by Start Index from the RGBController's LEDs
 /// vector). If a spot in the matrix is unused and does not map to an LED, it should be set to 0xFFFFFFFF.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ZoneMatrix {
     pub height: u32,
     pub width: u32,
     pub data: Vec<u32>,
 }
 
+/// A segment is a named range of LEDs inside a zone.
+///
+/// Requires protocol version 4.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    pub ty: ZoneType,
+    pub start_idx: u32,
+    pub leds_count: u32,
+}
+
 /// The Zone structure contains information about a zone. A zone is a logical grouping of LEDs defined by the
 /// RGBController implementation. LEDs in a zone must be contiguous in the RGBController's LEDs/Colors vectors.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Zone {
     pub name: String,
     pub ty: ZoneType,
@@ -59,10 +80,12 @@ pub struct Zone {
     pub leds_max: u32,
     pub leds_count: u32,
     pub matrix: Option<ZoneMatrix>,
+    /// Requires protocol version 4.
+    pub segments: Option<Vec<Segment>>,
 }
 
 bitflags::bitflags! {
-    #[derive(Debug, Clone, Copy)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct ModeFlags: u32 {
         /// Mode has speed parameter
         const SPEED = 1 << 0;
@@ -116,25 +139,39 @@ pub enum ColorMode {
 /// one or more colors each breath pulse. A mode may have multiple color options available, for instance a breathing
 /// mode that can either use one or more defined colors or just cycle through random colors. The available color modes
 /// for a given mode are set with the flags.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Mode {
     pub name: String,
     pub value: u32,
     pub flags: ModeFlags,
     pub speed_min: u32,
     pub speed_max: u32,
+    /// Requires protocol version 3.
+    pub brightness_min: Option<u32>,
+    /// Requires protocol version 3.
+    pub brightness_max: Option<u32>,
     pub colors_min: u32,
     pub colors_max: u32,
     pub speed: u32,
+    /// Requires protocol version 3.
+    pub brightness: Option<u32>,
     pub direction: u32,
     pub color_mode: ColorMode,
     pub colors: Vec<Rgb>,
 }
 
-#[derive(Debug)]
+impl<'a> From<&'a Mode> for Cow<'a, Mode> {
+    fn from(mode: &'a Mode) -> Self {
+        Cow::Borrowed(mode)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ControllerData {
     pub ty: ControllerType,
     pub name: String,
+    /// Requires protocol version 1.
+    pub vendor: Option<String>,
     pub description: String,
     pub version: String,
     pub serial: String,
@@ -146,50 +183,285 @@ pub struct ControllerData {
     pub colors: Vec<Rgb>,
 }
 
+impl ControllerData {
+    /// Serialize the controller data block, as found in the payload of a `ControllerData` response, using the layout
+    /// of the given protocol version.
+    pub fn encode(&self, protocol_version: u32) -> Vec<u8> {
+        let mut output = Vec::new();
+        unparse::controller_data(self, protocol_version, &mut output);
+        output
+    }
+
+    /// Parse a controller data block that was serialized with the layout of the given protocol version.
+    pub fn decode(bytes: &[u8], protocol_version: u32) -> Result<ControllerData, ProtocolError> {
+        let (rest, controller) = parse::controller_data(protocol_version, bytes)?;
+        if !rest.is_empty() {
+            return Err(ProtocolError::TrailingData(rest.len()));
+        }
+        Ok(controller)
+    }
+}
+
+/// A plugin loaded by the OpenRGB server, which can be talked to with [`Request::PluginSpecific`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plugin {
+    pub name: String,
+    pub description: String,
+    pub version: String,
+    /// The index to put in the plugin-specific requests.
+    pub index: u32,
+    /// The version of the plugin's own protocol.
+    pub protocol_version: u32,
+}
+
+/// An error that occured while reading a packet.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The underlying reader failed.
+    Io(std::io::Error),
+    /// The packet does not start with `ORGB`.
+    BadMagic,
+    /// The packet id is not one that this crate understands.
+    UnknownPacketId(u32),
+    /// A field has a value that does not correspond to any variant of its enum.
+    UnknownEnumValue { ty: &'static str, value: u32 },
+    /// The packet ended before all of its fields could be parsed.
+    Truncated,
+    /// A string is not null-terminated or is not valid utf-8.
+    InvalidString,
+    /// The packet contains this many bytes after its last field.
+    TrailingData(usize),
+    /// The server answered a request with a packet of the wrong kind.
+    UnexpectedResponse(Box<Response>),
+    /// The request has no response to wait for.
+    NoResponse,
+    /// The server did not answer the request in time.
+    Timeout,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "i/o error: {e}"),
+            ProtocolError::BadMagic => write!(f, "packet does not start with ORGB"),
+            ProtocolError::UnknownPacketId(id) => write!(f, "unknown packet id {id}"),
+            ProtocolError::UnknownEnumValue { ty, value } => write!(f, "unknown {ty} {value}"),
+            ProtocolError::Truncated => write!(f, "packet is truncated"),
+            ProtocolError::InvalidString => write!(f, "invalid string"),
+            ProtocolError::TrailingData(n) => write!(f, "{n} bytes of trailing data"),
+            ProtocolError::UnexpectedResponse(r) => write!(f, "unexpected response: {r:?}"),
+            ProtocolError::NoResponse => write!(f, "request has no response"),
+            ProtocolError::Timeout => write!(f, "timed out waiting for the response"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ProtocolError {
+    /// The error returned when the connection to the server is lost while waiting for a response.
+    pub(crate) fn disconnected() -> ProtocolError {
+        ProtocolError::Io(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            "the connection to the server has been lost",
+        ))
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+impl<I> nom::error::ParseError<I> for ProtocolError {
+    fn from_error_kind(_input: I, _kind: nom::error::ErrorKind) -> Self {
+        // The parsers operate on complete packets, so a failing primitive means that the input ran out
+        ProtocolError::Truncated
+    }
+
+    fn append(_input: I, _kind: nom::error::ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl From<nom::Err<ProtocolError>> for ProtocolError {
+    fn from(e: nom::Err<ProtocolError>) -> Self {
+        match e {
+            nom::Err::Incomplete(_) => ProtocolError::Truncated,
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-struct PacketHeader {
-    _dev_idx: u32,
+pub(crate) struct PacketHeader {
+    dev_idx: u32,
     pkt_id: u32,
-    pkt_size: u32,
+    pub(crate) pkt_size: u32,
 }
 
+/// What identifies the response to a request: its packet id and its device index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct PacketKey {
+    pub(crate) pkt_id: u32,
+    pub(crate) dev_idx: u32,
+}
+
+impl PacketHeader {
+    pub(crate) const SIZE: usize = 16;
+
+    pub(crate) fn key(&self) -> PacketKey {
+        PacketKey {
+            pkt_id: self.pkt_id,
+            dev_idx: self.dev_idx,
+        }
+    }
+
+    pub(crate) fn decode(bytes: &[u8; PacketHeader::SIZE]) -> Result<PacketHeader, ProtocolError> {
+        let (_, header) = parse::packet_header(bytes)?;
+        Ok(header)
+    }
+}
+
+/// A packet sent by the OpenRGB server.
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Response {
     ControllerCount(u32),
     ControllerData(ControllerData),
     ProtocolVersion(u32),
     DeviceListUpdated,
+    /// The names of the profiles saved on the server. Requires protocol version 2.
+    ProfileList(Vec<String>),
+    /// The plugins loaded by the server. Requires protocol version 4.
+    PluginList(Vec<Plugin>),
+    /// The answer of a plugin to a plugin-specific request, with the type of the request that it answers. The layout
+    /// of the data is defined by the plugin. Requires protocol version 4.
+    PluginSpecific {
+        pkt_type: u32,
+        data: Vec<u8>,
+    },
 }
 
 impl Response {
-    pub fn read_from<R: Read>(reader: &mut R) -> Result<Response, std::io::Error> {
+    /// Read one packet from the reader, expecting the layout of the given protocol version.
+    ///
+    /// When the error is anything other than [`ProtocolError::Io`] or [`ProtocolError::BadMagic`], the whole packet
+    /// has been consumed and the reader is ready to read the next one.
+    pub fn read_from<R: Read>(
+        reader: &mut R,
+        protocol_version: u32,
+    ) -> Result<Response, ProtocolError> {
+        Response::read_packet(reader, protocol_version)?.1
+    }
+
+    /// Read one packet from the reader, and return its key along with the result of parsing its data.
+    ///
+    /// The outer error means that the reader is out of sync, while the inner error is about this packet only.
+    pub(crate) fn read_packet<R: Read>(
+        reader: &mut R,
+        protocol_version: u32,
+    ) -> Result<(PacketKey, Result<Response, ProtocolError>), ProtocolError> {
         // Parse header
-        let mut header_bytes = [0u8; 16];
+        let mut header_bytes = [0u8; PacketHeader::SIZE];
         reader.read_exact(&mut header_bytes)?;
-        let (_, header) =
-            parse::packet_header(&header_bytes).expect("Could not parse packet header");
+        let header = PacketHeader::decode(&header_bytes)?;
+        let key = header.key();
 
         // Parse data
         let mut data_bytes = vec![0u8; header.pkt_size as usize];
         reader.read_exact(&mut data_bytes)?;
-        let (rest, response) =
-            parse::response(header, &data_bytes).expect("Could not parse packet data");
+        Ok((key, Response::decode(header, protocol_version, &data_bytes)))
+    }
+
+    /// Write the response to the writer, using the layout of the given protocol version.
+    ///
+    /// The device index goes into the packet header. The OpenRGB server sets it to the index of the controller that
+    /// the response is about, and to 0 for responses that are not about a controller.
+    pub fn write_to<W: Write>(
+        &self,
+        writer: &mut W,
+        dev_idx: u32,
+        protocol_version: u32,
+    ) -> Result<(), std::io::Error> {
+        let mut data = Vec::new();
+        let pkt_id = match self {
+            Response::ControllerCount(c) => {
+                unparse::u32(*c, &mut data);
+                0
+            }
+            Response::ControllerData(c) => {
+                unparse::controller_data(c, protocol_version, &mut data);
+                1
+            }
+            Response::ProtocolVersion(v) => {
+                unparse::u32(*v, &mut data);
+                40
+            }
+            Response::DeviceListUpdated => 100,
+            Response::ProfileList(profiles) => {
+                unparse::profile_list(profiles, &mut data);
+                150
+            }
+            Response::PluginList(plugins) => {
+                unparse::plugin_list(plugins, &mut data);
+                200
+            }
+            Response::PluginSpecific {
+                pkt_type,
+                data: payload,
+            } => {
+                unparse::u32(*pkt_type, &mut data);
+                data.extend(payload);
+                201
+            }
+        };
+
+        let mut output = Vec::new();
+        output.extend_from_slice(b"ORGB");
+        unparse::u32(dev_idx, &mut output);
+        unparse::u32(pkt_id, &mut output);
+        unparse::u32(data.len() as u32, &mut output);
+        output.extend(data);
+        writer.write_all(&output)
+    }
+
+    /// Parse the data of a packet whose header has already been read.
+    pub(crate) fn decode(
+        header: PacketHeader,
+        protocol_version: u32,
+        data_bytes: &[u8],
+    ) -> Result<Response, ProtocolError> {
+        let (rest, response) = parse::response(header, protocol_version, data_bytes)?;
 
         // Check that there is no unparsed data
-        assert_eq!(rest.len(), 0);
+        if !rest.is_empty() {
+            return Err(ProtocolError::TrailingData(rest.len()));
+        }
 
         Ok(response)
     }
 }
 
-#[derive(Debug, Clone)]
+/// A packet sent by a client to the OpenRGB server.
+///
+/// The variants borrow their payload when sent, and own it when read with [`Request::read_from`].
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Request<'a> {
     ControllerCount,
     ControllerData {
         controller_idx: u32,
     },
     ProtocolVersion(u32),
-    SetClientName(&'a str),
+    SetClientName(Cow<'a, str>),
     ResizeZone {
         controller_idx: u32,
         zone_idx: u32,
@@ -197,12 +469,12 @@ pub enum Request<'a> {
     },
     UpdateLeds {
         controller_idx: u32,
-        colors: &'a [Rgb],
+        colors: Cow<'a, [Rgb]>,
     },
     UpdateZoneLeds {
         controller_idx: u32,
         zone_idx: u32,
-        colors: &'a [Rgb],
+        colors: Cow<'a, [Rgb]>,
     },
     UpdateSingleLed {
         controller_idx: u32,
@@ -215,22 +487,71 @@ pub enum Request<'a> {
     UpdateMode {
         controller_idx: u32,
         mode_idx: u32,
-        mode: &'a Mode,
+        mode: Cow<'a, Mode>,
     },
     SaveMode {
         controller_idx: u32,
         mode_idx: u32,
-        mode: &'a Mode,
+        mode: Cow<'a, Mode>,
+    },
+    /// Requires protocol version 2.
+    ProfileList,
+    /// Save the current state of the devices under this profile name. Requires protocol version 2.
+    SaveProfile(Cow<'a, str>),
+    /// Requires protocol version 2.
+    LoadProfile(Cow<'a, str>),
+    /// Requires protocol version 2.
+    DeleteProfile(Cow<'a, str>),
+    /// Requires protocol version 4.
+    PluginList,
+    /// A request to a plugin, whose meaning is defined by the plugin. Requires protocol version 4.
+    ///
+    /// The plugin may or may not answer with a [`Response::PluginSpecific`].
+    PluginSpecific {
+        /// See [`Plugin::index`].
+        plugin_idx: u32,
+        pkt_type: u32,
+        data: Cow<'a, [u8]>,
     },
 }
 
 impl Request<'_> {
-    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+    /// The key of the packet that the server sends back to answer this request, if any.
+    pub(crate) fn response_key(&self) -> Option<PacketKey> {
+        let (pkt_id, dev_idx) = match *self {
+            Request::ControllerCount => (0, 0),
+            Request::ControllerData { controller_idx } => (1, controller_idx),
+            Request::ProtocolVersion(_) => (40, 0),
+            Request::ProfileList => (150, 0),
+            Request::PluginList => (200, 0),
+            // The server puts the type of the request where the device index goes, not the index of the plugin
+            Request::PluginSpecific { pkt_type, .. } => (201, pkt_type),
+            Request::SetClientName(_)
+            | Request::ResizeZone { .. }
+            | Request::UpdateLeds { .. }
+            | Request::UpdateZoneLeds { .. }
+            | Request::UpdateSingleLed { .. }
+            | Request::SetCustomMode { .. }
+            | Request::UpdateMode { .. }
+            | Request::SaveMode { .. }
+            | Request::SaveProfile(_)
+            | Request::LoadProfile(_)
+            | Request::DeleteProfile(_) => return None,
+        };
+        Some(PacketKey { pkt_id, dev_idx })
+    }
+
+    /// Write the request to the writer, using the layout of the given protocol version.
+    pub fn write_to<W: Write>(
+        &self,
+        writer: &mut W,
+        protocol_version: u32,
+    ) -> Result<(), std::io::Error> {
         let mut output = Vec::new();
         let output = &mut output;
         output.extend_from_slice(b"ORGB");
 
-        match *self {
+        match self {
             Request::ControllerCount => {
                 unparse::u32(0, output); // dev_idx
                 unparse::u32(0, output); // pkt_id
@@ -240,44 +561,156 @@ impl Request<'_> {
                 unparse::u32(0, output); // dev_idx
                 unparse::u32(40, output); // pkt_id
                 unparse::u32(4, output); // pkt_size
-                unparse::u32(v, output);
+                unparse::u32(*v, output);
             }
             Request::ControllerData { controller_idx } => {
-                unparse::u32(controller_idx, output); // dev_idx
+                unparse::u32(*controller_idx, output); // dev_idx
                 unparse::u32(1, output); // pkt_id
-                unparse::u32(0, output); // pkt_size
-            }
-            Request::SetClientName(name) => {
-                let len = name.as_bytes().len() + 1;
-                unparse::u32(0, output); // dev_idx
-                unparse::u32(50, output); // pkt_id
-                unparse::u32(len as u32, output); // pkt_size
-                output.extend(name.as_bytes());
-                output.extend(b"\0");
+                if protocol_version >= 1 {
+                    // Tell the server which layout to answer with
+                    unparse::u32(4, output); // pkt_size
+                    unparse::u32(protocol_version, output);
+                } else {
+                    unparse::u32(0, output); // pkt_size
+                }
             }
+            Request::SetClientName(name) => unparse::name_request(50, name, output),
             Request::UpdateLeds {
                 controller_idx,
                 colors,
             } => {
                 let len = 4 + 2 + 4 * colors.len();
-                unparse::u32(controller_idx, output); // dev_idx
+                unparse::u32(*controller_idx, output); // dev_idx
                 unparse::u32(1050, output); // pkt_id
                 unparse::u32(len as u32, output); // pkt_size
                 unparse::u32(len as u32, output);
                 unparse::u16(colors.len() as u16, output);
-                for c in colors {
+                for c in colors.iter() {
                     unparse::color(*c, output);
                 }
             }
-            Request::ResizeZone { .. } => todo!(),
-            Request::SaveMode { .. } => todo!(),
-            Request::SetCustomMode { .. } => todo!(),
-            Request::UpdateMode { .. } => todo!(),
-            Request::UpdateZoneLeds { .. } => todo!(),
-            Request::UpdateSingleLed { .. } => todo!(),
+            Request::UpdateZoneLeds {
+                controller_idx,
+                zone_idx,
+                colors,
+            } => {
+                let len = 4 + 4 + 2 + 4 * colors.len();
+                unparse::u32(*controller_idx, output); // dev_idx
+                unparse::u32(1051, output); // pkt_id
+                unparse::u32(len as u32, output); // pkt_size
+                unparse::u32(len as u32, output);
+                unparse::u32(*zone_idx, output);
+                unparse::u16(colors.len() as u16, output);
+                for c in colors.iter() {
+                    unparse::color(*c, output);
+                }
+            }
+            Request::UpdateSingleLed {
+                controller_idx,
+                led_idx,
+                color,
+            } => {
+                unparse::u32(*controller_idx, output); // dev_idx
+                unparse::u32(1052, output); // pkt_id
+                unparse::u32(8, output); // pkt_size
+                unparse::u32(*led_idx, output);
+                unparse::color(*color, output);
+            }
+            Request::ResizeZone {
+                controller_idx,
+                zone_idx,
+                new_size,
+            } => {
+                unparse::u32(*controller_idx, output); // dev_idx
+                unparse::u32(1000, output); // pkt_id
+                unparse::u32(8, output); // pkt_size
+                unparse::u32(*zone_idx, output);
+                unparse::u32(*new_size, output);
+            }
+            Request::SetCustomMode { controller_idx } => {
+                unparse::u32(*controller_idx, output); // dev_idx
+                unparse::u32(1100, output); // pkt_id
+                unparse::u32(0, output); // pkt_size
+            }
+            Request::UpdateMode {
+                controller_idx,
+                mode_idx,
+                mode,
+            } => unparse::mode_request(
+                *controller_idx,
+                1101,
+                *mode_idx,
+                mode,
+                protocol_version,
+                output,
+            ),
+            Request::SaveMode {
+                controller_idx,
+                mode_idx,
+                mode,
+            } => unparse::mode_request(
+                *controller_idx,
+                1102,
+                *mode_idx,
+                mode,
+                protocol_version,
+                output,
+            ),
+            Request::ProfileList => {
+                unparse::u32(0, output); // dev_idx
+                unparse::u32(150, output); // pkt_id
+                unparse::u32(0, output); // pkt_size
+            }
+            Request::SaveProfile(name) => unparse::name_request(151, name, output),
+            Request::LoadProfile(name) => unparse::name_request(152, name, output),
+            Request::DeleteProfile(name) => unparse::name_request(153, name, output),
+            Request::PluginList => {
+                unparse::u32(0, output); // dev_idx
+                unparse::u32(200, output); // pkt_id
+                unparse::u32(0, output); // pkt_size
+            }
+            Request::PluginSpecific {
+                plugin_idx,
+                pkt_type,
+                data,
+            } => {
+                unparse::u32(*plugin_idx, output); // dev_idx
+                unparse::u32(201, output); // pkt_id
+                unparse::u32(4 + data.len() as u32, output); // pkt_size
+                unparse::u32(*pkt_type, output);
+                output.extend(data.iter());
+            }
         }
 
-        writer.write(&output).map(|_| ())
+        writer.write_all(output)
+    }
+}
+
+impl Request<'static> {
+    /// Read one request from the reader, expecting the layout of the given protocol version.
+    ///
+    /// When the error is anything other than [`ProtocolError::Io`] or [`ProtocolError::BadMagic`], the whole packet
+    /// has been consumed and the reader is ready to read the next one.
+    pub fn read_from<R: Read>(
+        reader: &mut R,
+        protocol_version: u32,
+    ) -> Result<Request<'static>, ProtocolError> {
+        // Parse header
+        let mut header_bytes = [0u8; PacketHeader::SIZE];
+        reader.read_exact(&mut header_bytes)?;
+        let header = PacketHeader::decode(&header_bytes)?;
+
+        // Parse data
+        let mut data_bytes = vec![0u8; header.pkt_size as usize];
+        reader.read_exact(&mut data_bytes)?;
+        let (rest, request) = parse::request(header, protocol_version, &data_bytes)?;
+
+        // Check that there is no unparsed data
+        if !rest.is_empty() {
+            return Err(ProtocolError::TrailingData(rest.len()));
+        }
+
+        Ok(request)
     }
 }
 
@@ -286,52 +719,66 @@ mod parse {
 
     use nom::{
         bytes::complete::{tag, take},
-        combinator::map,
+        combinator::{map, opt},
         multi::count,
-        number::{complete, Endianness},
-        IResult,
+        number::complete,
     };
 
-    fn u16(input: &[u8]) -> IResult<&[u8], u16> {
-        complete::u16(Endianness::Native)(input)
+    type IResult<'a, T> = nom::IResult<&'a [u8], T, ProtocolError>;
+
+    fn fail<T>(e: ProtocolError) -> Result<T, nom::Err<ProtocolError>> {
+        Err(nom::Err::Failure(e))
+    }
+
+    fn u16(input: &[u8]) -> IResult<'_, u16> {
+        complete::le_u16(input)
     }
 
-    fn u32(input: &[u8]) -> IResult<&[u8], u32> {
-        complete::u32(Endianness::Native)(input)
+    fn u32(input: &[u8]) -> IResult<'_, u32> {
+        complete::le_u32(input)
     }
 
-    fn controller_type(input: &[u8]) -> IResult<&[u8], ControllerType> {
-        let (input, x) = u32(input)?;
-        let x = ControllerType::try_from(x).expect("Unknown controller type");
-        Ok((input, x))
+    fn enum_value<'a, T: TryFrom<u32>>(ty: &'static str, input: &'a [u8]) -> IResult<'a, T> {
+        let (input, value) = u32(input)?;
+        match T::try_from(value) {
+            Ok(x) => Ok((input, x)),
+            Err(_) => fail(ProtocolError::UnknownEnumValue { ty, value }),
+        }
     }
 
-    fn zone_type(input: &[u8]) -> IResult<&[u8], ZoneType> {
-        let (input, x) = u32(input)?;
-        let x = ZoneType::try_from(x).expect("Unknown zone type");
-        Ok((input, x))
+    fn controller_type(input: &[u8]) -> IResult<'_, ControllerType> {
+        enum_value("controller type", input)
     }
 
-    fn color_mode(input: &[u8]) -> IResult<&[u8], ColorMode> {
-        let (input, x) = u32(input)?;
-        let x = ColorMode::try_from(x).expect("Unknown color mode");
-        Ok((input, x))
+    fn zone_type(input: &[u8]) -> IResult<'_, ZoneType> {
+        enum_value("zone type", input)
     }
 
-    fn null_terminated_string(len_with_terminator: u16, input: &[u8]) -> IResult<&[u8], &str> {
-        let (input, string) = take(len_with_terminator - 1)(input)?;
-        let string = std::str::from_utf8(string).expect("Received a string that is not utf-8");
-        let (input, _) = tag(b"\0")(input)?;
+    fn color_mode(input: &[u8]) -> IResult<'_, ColorMode> {
+        enum_value("color mode", input)
+    }
+
+    fn null_terminated_string(len_with_terminator: u16, input: &[u8]) -> IResult<'_, &str> {
+        let Some(len) = len_with_terminator.checked_sub(1) else {
+            return fail(ProtocolError::InvalidString);
+        };
+        let (input, string) = take(len)(input)?;
+        let Ok(string) = std::str::from_utf8(string) else {
+            return fail(ProtocolError::InvalidString);
+        };
+        let (input, _) = tag(b"\0")(input).map_err(|_: nom::Err<ProtocolError>| {
+            nom::Err::Failure(ProtocolError::InvalidString)
+        })?;
         Ok((input, string))
     }
 
-    fn color(input: &[u8]) -> IResult<&[u8], Rgb> {
-        let (input, color_int) = u32(input)?;
-        let color_bytes = color_int.to_ne_bytes();
+    fn color(input: &[u8]) -> IResult<'_, Rgb> {
+        // The color is a little-endian u32 0x00BBGGRR, so the bytes are R, G, B and padding
+        let (input, color_bytes) = take(4usize)(input)?;
         Ok((input, Rgb(color_bytes[0], color_bytes[1], color_bytes[2])))
     }
 
-    fn led(input: &[u8]) -> IResult<&[u8], Led> {
+    fn led(input: &[u8]) -> IResult<'_, Led> {
         let (input, name_len) = u16(input)?;
         let (input, name) = null_terminated_string(name_len, input)?;
         let (input, value) = u32(input)?;
@@ -344,10 +791,10 @@ mod parse {
         ))
     }
 
-    fn zone_matrix(input: &[u8]) -> IResult<&[u8], ZoneMatrix> {
+    fn zone_matrix(input: &[u8]) -> IResult<'_, ZoneMatrix> {
         let (input, height) = u32(input)?;
         let (input, width) = u32(input)?;
-        let (input, data) = count(u32, (height * width) as usize)(input)?;
+        let (input, data) = count(u32, height as usize * width as usize)(input)?;
         Ok((
             input,
             ZoneMatrix {
@@ -358,7 +805,24 @@ mod parse {
         ))
     }
 
-    fn zone(input: &[u8]) -> IResult<&[u8], Zone> {
+    fn segment(input: &[u8]) -> IResult<'_, Segment> {
+        let (input, name_len) = u16(input)?;
+        let (input, name) = null_terminated_string(name_len, input)?;
+        let (input, ty) = zone_type(input)?;
+        let (input, start_idx) = u32(input)?;
+        let (input, leds_count) = u32(input)?;
+        Ok((
+            input,
+            Segment {
+                name: name.into(),
+                ty,
+                start_idx,
+                leds_count,
+            },
+        ))
+    }
+
+    pub(super) fn zone(version: u32, input: &[u8]) -> IResult<'_, Zone> {
         let (input, name_len) = u16(input)?;
         let (input, name) = null_terminated_string(name_len, input)?;
         let (input, ty) = zone_type(input)?;
@@ -372,6 +836,13 @@ mod parse {
             let (input, matrix) = zone_matrix(input)?;
             (input, Some(matrix))
         };
+        let (input, segments) = if version >= 4 {
+            let (input, num_segments) = u16(input)?;
+            let (input, segments) = count(segment, num_segments as usize)(input)?;
+            (input, Some(segments))
+        } else {
+            (input, None)
+        };
         Ok((
             input,
             Zone {
@@ -381,20 +852,38 @@ mod parse {
                 leds_max,
                 leds_count,
                 matrix,
+                segments,
             },
         ))
     }
 
-    fn mode(input: &[u8]) -> IResult<&[u8], Mode> {
+    /// Parse an optional field that is only present from the given protocol version onwards.
+    fn since<'a, T>(
+        min_version: u32,
+        version: u32,
+        parser: fn(&[u8]) -> IResult<'_, T>,
+        input: &'a [u8],
+    ) -> IResult<'a, Option<T>> {
+        if version >= min_version {
+            map(parser, Some)(input)
+        } else {
+            Ok((input, None))
+        }
+    }
+
+    pub(super) fn mode(version: u32, input: &[u8]) -> IResult<'_, Mode> {
         let (input, name_len) = u16(input)?;
         let (input, name) = null_terminated_string(name_len, input)?;
         let (input, value) = u32(input)?;
         let (input, flags) = u32(input)?;
         let (input, speed_min) = u32(input)?;
         let (input, speed_max) = u32(input)?;
+        let (input, brightness_min) = since(3, version, u32, input)?;
+        let (input, brightness_max) = since(3, version, u32, input)?;
         let (input, colors_min) = u32(input)?;
         let (input, colors_max) = u32(input)?;
         let (input, speed) = u32(input)?;
+        let (input, brightness) = since(3, version, u32, input)?;
         let (input, direction) = u32(input)?;
         let (input, color_mode) = color_mode(input)?;
         let (input, num_colors) = u16(input)?;
@@ -407,21 +896,34 @@ mod parse {
                 flags: ModeFlags::from_bits_retain(flags),
                 speed_min,
                 speed_max,
+                brightness_min,
+                brightness_max,
                 colors_min,
                 color_mode,
                 colors_max,
                 speed,
+                brightness,
                 direction,
                 colors,
             },
         ))
     }
 
-    fn controller_data(input: &[u8]) -> IResult<&[u8], ControllerData> {
+    pub(super) fn controller_data(
+        protocol_version: u32,
+        input: &[u8],
+    ) -> IResult<'_, ControllerData> {
         let (input, _size) = u32(input)?;
         let (input, ty) = controller_type(input)?;
         let (input, name_len) = u16(input)?;
         let (input, name) = null_terminated_string(name_len, input)?;
+        let (input, vendor) = if protocol_version >= 1 {
+            let (input, vendor_len) = u16(input)?;
+            let (input, vendor) = null_terminated_string(vendor_len, input)?;
+            (input, Some(vendor.into()))
+        } else {
+            (input, None)
+        };
         let (input, description_len) = u16(input)?;
         let (input, description) = null_terminated_string(description_len, input)?;
         let (input, version_len) = u16(input)?;
@@ -432,9 +934,9 @@ mod parse {
         let (input, location) = null_terminated_string(location_len, input)?;
         let (input, num_modes) = u16(input)?;
         let (input, active_mode) = u32(input)?;
-        let (input, modes) = count(mode, num_modes as usize)(input)?;
+        let (input, modes) = count(|i| mode(protocol_version, i), num_modes as usize)(input)?;
         let (input, num_zones) = u16(input)?;
-        let (input, zones) = count(zone, num_zones as usize)(input)?;
+        let (input, zones) = count(|i| zone(protocol_version, i), num_zones as usize)(input)?;
         let (input, num_leds) = u16(input)?;
         let (input, leds) = count(led, num_leds as usize)(input)?;
         let (input, num_colors) = u16(input)?;
@@ -444,6 +946,7 @@ mod parse {
             ControllerData {
                 ty,
                 name: name.into(),
+                vendor,
                 description: description.into(),
                 version: version.into(),
                 serial: serial.into(),
@@ -457,28 +960,188 @@ mod parse {
         ))
     }
 
-    pub(super) fn packet_header(input: &[u8]) -> IResult<&[u8], PacketHeader> {
-        let (input, _) = tag(b"ORGB")(input)?;
+    pub(super) fn packet_header(input: &[u8]) -> IResult<'_, PacketHeader> {
+        let (input, _) = tag(b"ORGB")(input)
+            .map_err(|_: nom::Err<ProtocolError>| nom::Err::Failure(ProtocolError::BadMagic))?;
         let (input, dev_idx) = u32(input)?;
         let (input, pkt_id) = u32(input)?;
         let (input, pkt_size) = u32(input)?;
         Ok((
             input,
             PacketHeader {
-                _dev_idx: dev_idx,
+                dev_idx,
                 pkt_id,
                 pkt_size,
             },
         ))
     }
 
-    pub(super) fn response(header: PacketHeader, input: &[u8]) -> IResult<&[u8], Response> {
+    pub(super) fn response(
+        header: PacketHeader,
+        version: u32,
+        input: &[u8],
+    ) -> IResult<'_, Response> {
         match header.pkt_id {
             0 => map(u32, Response::ControllerCount)(input),
-            1 => map(controller_data, Response::ControllerData)(input),
+            1 => map(|i| controller_data(version, i), Response::ControllerData)(input),
             40 => map(u32, Response::ProtocolVersion)(input),
             100 => Ok((input, Response::DeviceListUpdated)),
-            _ => panic!("Unknown command id"),
+            150 => map(profile_list, Response::ProfileList)(input),
+            200 => map(plugin_list, Response::PluginList)(input),
+            201 => {
+                let (input, pkt_type) = u32(input)?;
+                // The data is opaque, so take all of it
+                let response = Response::PluginSpecific {
+                    pkt_type,
+                    data: input.to_vec(),
+                };
+                Ok((&[], response))
+            }
+            id => fail(ProtocolError::UnknownPacketId(id)),
+        }
+    }
+
+    fn profile_list(input: &[u8]) -> IResult<'_, Vec<String>> {
+        let (input, _size) = u32(input)?;
+        let (input, num_profiles) = u16(input)?;
+        count(profile_name, num_profiles as usize)(input)
+    }
+
+    fn profile_name(input: &[u8]) -> IResult<'_, String> {
+        let (input, name_len) = u16(input)?;
+        let (input, name) = null_terminated_string(name_len, input)?;
+        Ok((input, name.into()))
+    }
+
+    fn plugin_list(input: &[u8]) -> IResult<'_, Vec<Plugin>> {
+        let (input, _size) = u32(input)?;
+        let (input, num_plugins) = u16(input)?;
+        count(plugin, num_plugins as usize)(input)
+    }
+
+    fn plugin(input: &[u8]) -> IResult<'_, Plugin> {
+        let (input, name_len) = u16(input)?;
+        let (input, name) = null_terminated_string(name_len, input)?;
+        let (input, description_len) = u16(input)?;
+        let (input, description) = null_terminated_string(description_len, input)?;
+        let (input, version_len) = u16(input)?;
+        let (input, version) = null_terminated_string(version_len, input)?;
+        let (input, index) = u32(input)?;
+        let (input, protocol_version) = u32(input)?;
+        Ok((
+            input,
+            Plugin {
+                name: name.into(),
+                description: description.into(),
+                version: version.into(),
+                index,
+                protocol_version,
+            },
+        ))
+    }
+
+    /// A null-terminated string that fills the whole packet, without a length prefix.
+    fn name(input: &[u8]) -> IResult<'_, Cow<'static, str>> {
+        let (input, name) = null_terminated_string(input.len() as u16, input)?;
+        Ok((input, name.to_owned().into()))
+    }
+
+    fn colors(input: &[u8]) -> IResult<'_, Vec<Rgb>> {
+        let (input, num_colors) = u16(input)?;
+        count(color, num_colors as usize)(input)
+    }
+
+    pub(super) fn request(
+        header: PacketHeader,
+        version: u32,
+        input: &[u8],
+    ) -> IResult<'_, Request<'static>> {
+        let controller_idx = header.dev_idx;
+        match header.pkt_id {
+            0 => Ok((input, Request::ControllerCount)),
+            1 => {
+                // Since protocol version 1, the client also sends its protocol version
+                let (input, _) = opt(u32)(input)?;
+                Ok((input, Request::ControllerData { controller_idx }))
+            }
+            40 => map(u32, Request::ProtocolVersion)(input),
+            50 => map(name, Request::SetClientName)(input),
+            1000 => {
+                let (input, zone_idx) = u32(input)?;
+                let (input, new_size) = u32(input)?;
+                let request = Request::ResizeZone {
+                    controller_idx,
+                    zone_idx,
+                    new_size,
+                };
+                Ok((input, request))
+            }
+            1050 => {
+                let (input, _size) = u32(input)?;
+                let (input, colors) = colors(input)?;
+                let request = Request::UpdateLeds {
+                    controller_idx,
+                    colors: colors.into(),
+                };
+                Ok((input, request))
+            }
+            1051 => {
+                let (input, _size) = u32(input)?;
+                let (input, zone_idx) = u32(input)?;
+                let (input, colors) = colors(input)?;
+                let request = Request::UpdateZoneLeds {
+                    controller_idx,
+                    zone_idx,
+                    colors: colors.into(),
+                };
+                Ok((input, request))
+            }
+            1052 => {
+                let (input, led_idx) = u32(input)?;
+                let (input, color) = color(input)?;
+                let request = Request::UpdateSingleLed {
+                    controller_idx,
+                    led_idx,
+                    color,
+                };
+                Ok((input, request))
+            }
+            1100 => Ok((input, Request::SetCustomMode { controller_idx })),
+            pkt_id @ (1101 | 1102) => {
+                let (input, _size) = u32(input)?;
+                let (input, mode_idx) = u32(input)?;
+                let (input, mode) = mode(version, input)?;
+                let mode = Cow::Owned(mode);
+                let request = if pkt_id == 1101 {
+                    Request::UpdateMode {
+                        controller_idx,
+                        mode_idx,
+                        mode,
+                    }
+                } else {
+                    Request::SaveMode {
+                        controller_idx,
+                        mode_idx,
+                        mode,
+                    }
+                };
+                Ok((input, request))
+            }
+            150 => Ok((input, Request::ProfileList)),
+            151 => map(name, Request::SaveProfile)(input),
+            152 => map(name, Request::LoadProfile)(input),
+            153 => map(name, Request::DeleteProfile)(input),
+            200 => Ok((input, Request::PluginList)),
+            201 => {
+                let (input, pkt_type) = u32(input)?;
+                let request = Request::PluginSpecific {
+                    plugin_idx: controller_idx,
+                    pkt_type,
+                    data: input.to_vec().into(),
+                };
+                Ok((&[], request))
+            }
+            id => fail(ProtocolError::UnknownPacketId(id)),
         }
     }
 }
@@ -487,14 +1150,715 @@ mod unparse {
     use super::*;
 
     pub fn u16(x: u16, output: &mut Vec<u8>) {
-        output.extend(x.to_ne_bytes());
+        output.extend(x.to_le_bytes());
     }
 
     pub fn u32(x: u32, output: &mut Vec<u8>) {
-        output.extend(x.to_ne_bytes())
+        output.extend(x.to_le_bytes())
     }
 
     pub fn color(c: Rgb, output: &mut Vec<u8>) {
-        u32(u32::from_ne_bytes([c.0, c.1, c.2, 0x00]), output);
+        output.extend([c.0, c.1, c.2, 0x00]);
+    }
+
+    pub fn string(s: &str, output: &mut Vec<u8>) {
+        u16(s.len() as u16 + 1, output);
+        output.extend(s.as_bytes());
+        output.extend(b"\0");
+    }
+
+    /// Write an optional field that is only present from the given protocol version onwards.
+    fn since(min_version: u32, version: u32, x: Option<u32>, output: &mut Vec<u8>) {
+        if version >= min_version {
+            u32(x.unwrap_or_default(), output);
+        }
+    }
+
+    pub fn mode(m: &Mode, version: u32, output: &mut Vec<u8>) {
+        string(&m.name, output);
+        u32(m.value, output);
+        u32(m.flags.bits(), output);
+        u32(m.speed_min, output);
+        u32(m.speed_max, output);
+        since(3, version, m.brightness_min, output);
+        since(3, version, m.brightness_max, output);
+        u32(m.colors_min, output);
+        u32(m.colors_max, output);
+        u32(m.speed, output);
+        since(3, version, m.brightness, output);
+        u32(m.direction, output);
+        u32(m.color_mode.into(), output);
+        colors(&m.colors, output);
+    }
+
+    fn colors(colors: &[Rgb], output: &mut Vec<u8>) {
+        u16(colors.len() as u16, output);
+        for c in colors {
+            color(*c, output);
+        }
+    }
+
+    fn led(l: &Led, output: &mut Vec<u8>) {
+        string(&l.name, output);
+        u32(l.value, output);
+    }
+
+    fn segment(s: &Segment, output: &mut Vec<u8>) {
+        string(&s.name, output);
+        u32(s.ty.into(), output);
+        u32(s.start_idx, output);
+        u32(s.leds_count, output);
+    }
+
+    pub fn zone(z: &Zone, version: u32, output: &mut Vec<u8>) {
+        string(&z.name, output);
+        u32(z.ty.into(), output);
+        u32(z.leds_min, output);
+        u32(z.leds_max, output);
+        u32(z.leds_count, output);
+        match &z.matrix {
+            Some(matrix) => {
+                u16((4 + 4 + 4 * matrix.data.len()) as u16, output);
+                u32(matrix.height, output);
+                u32(matrix.width, output);
+                for x in &matrix.data {
+                    u32(*x, output);
+                }
+            }
+            None => u16(0, output),
+        }
+        if version >= 4 {
+            let segments = z.segments.as_deref().unwrap_or_default();
+            u16(segments.len() as u16, output);
+            for s in segments {
+                segment(s, output);
+            }
+        }
+    }
+
+    pub fn controller_data(c: &ControllerData, version: u32, output: &mut Vec<u8>) {
+        let mut data = Vec::new();
+        let data = &mut data;
+        u32(c.ty.into(), data);
+        string(&c.name, data);
+        if version >= 1 {
+            string(c.vendor.as_deref().unwrap_or_default(), data);
+        }
+        string(&c.description, data);
+        string(&c.version, data);
+        string(&c.serial, data);
+        string(&c.location, data);
+        u16(c.modes.len() as u16, data);
+        u32(c.active_mode, data);
+        for m in &c.modes {
+            mode(m, version, data);
+        }
+        u16(c.zones.len() as u16, data);
+        for z in &c.zones {
+            zone(z, version, data);
+        }
+        u16(c.leds.len() as u16, data);
+        for l in &c.leds {
+            led(l, data);
+        }
+        colors(&c.colors, data);
+
+        // The size includes itself
+        u32(4 + data.len() as u32, output);
+        output.extend(data.iter());
+    }
+
+    pub fn profile_list(profiles: &[String], output: &mut Vec<u8>) {
+        let mut data = Vec::new();
+        u16(profiles.len() as u16, &mut data);
+        for p in profiles {
+            string(p, &mut data);
+        }
+
+        // The size includes itself
+        u32(4 + data.len() as u32, output);
+        output.extend(data);
+    }
+
+    pub fn plugin_list(plugins: &[Plugin], output: &mut Vec<u8>) {
+        let mut data = Vec::new();
+        u16(plugins.len() as u16, &mut data);
+        for p in plugins {
+            string(&p.name, &mut data);
+            string(&p.description, &mut data);
+            string(&p.version, &mut data);
+            u32(p.index, &mut data);
+            u32(p.protocol_version, &mut data);
+        }
+
+        // The size includes itself
+        u32(4 + data.len() as u32, output);
+        output.extend(data);
+    }
+
+    /// A request whose payload is a single null-terminated string, without a length prefix.
+    pub fn name_request(pkt_id: u32, name: &str, output: &mut Vec<u8>) {
+        u32(0, output); // dev_idx
+        u32(pkt_id, output); // pkt_id
+        u32(name.len() as u32 + 1, output); // pkt_size
+        output.extend(name.as_bytes());
+        output.extend(b"\0");
+    }
+
+    /// Body shared by the UpdateMode and SaveMode requests.
+    pub fn mode_request(
+        controller_idx: u32,
+        pkt_id: u32,
+        mode_idx: u32,
+        m: &Mode,
+        version: u32,
+        output: &mut Vec<u8>,
+    ) {
+        let mut mode_bytes = Vec::new();
+        mode(m, version, &mut mode_bytes);
+        let len = 4 + 4 + mode_bytes.len();
+        u32(controller_idx, output); // dev_idx
+        u32(pkt_id, output); // pkt_id
+        u32(len as u32, output); // pkt_size
+        u32(len as u32, output);
+        u32(mode_idx, output);
+        output.extend(mode_bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(request: Request) -> Vec<u8> {
+        let mut output = Vec::new();
+        request.write_to(&mut output, 0).unwrap();
+        output
+    }
+
+    fn header(dev_idx: u32, pkt_id: u32, pkt_size: u32) -> Vec<u8> {
+        let mut output = b"ORGB".to_vec();
+        output.extend(dev_idx.to_le_bytes());
+        output.extend(pkt_id.to_le_bytes());
+        output.extend(pkt_size.to_le_bytes());
+        output
+    }
+
+    fn breathing_mode() -> Mode {
+        Mode {
+            name: "Breathing".into(),
+            value: 3,
+            flags: ModeFlags::SPEED | ModeFlags::SPECIFIC_SETTINGS,
+            speed_min: 0,
+            speed_max: 4,
+            brightness_min: None,
+            brightness_max: None,
+            colors_min: 1,
+            colors_max: 2,
+            speed: 2,
+            brightness: None,
+            direction: 0,
+            color_mode: ColorMode::ModeSpecific,
+            colors: vec![Rgb(0xff, 0x80, 0x00), Rgb(0x00, 0x10, 0x20)],
+        }
+    }
+
+    #[test]
+    fn resize_zone() {
+        let bytes = encode(Request::ResizeZone {
+            controller_idx: 2,
+            zone_idx: 1,
+            new_size: 30,
+        });
+        let mut expected = header(2, 1000, 8);
+        expected.extend([1, 0, 0, 0, 30, 0, 0, 0]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn controller_data_sends_the_protocol_version() {
+        assert_eq!(
+            encode(Request::ControllerData { controller_idx: 2 }),
+            header(2, 1, 0)
+        );
+
+        // Since protocol version 1, the server answers with the layout of the version sent with the request
+        let mut bytes = Vec::new();
+        Request::ControllerData { controller_idx: 2 }
+            .write_to(&mut bytes, 3)
+            .unwrap();
+        let mut expected = header(2, 1, 4);
+        expected.extend([3, 0, 0, 0]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn update_zone_leds() {
+        let bytes = encode(Request::UpdateZoneLeds {
+            controller_idx: 1,
+            zone_idx: 3,
+            colors: vec![Rgb(1, 2, 3), Rgb(4, 5, 6)].into(),
+        });
+        let mut expected = header(1, 1051, 18);
+        expected.extend([18, 0, 0, 0, 3, 0, 0, 0, 2, 0]);
+        expected.extend([1, 2, 3, 0, 4, 5, 6, 0]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn update_single_led() {
+        let bytes = encode(Request::UpdateSingleLed {
+            controller_idx: 4,
+            led_idx: 7,
+            color: Rgb(0xaa, 0xbb, 0xcc),
+        });
+        let mut expected = header(4, 1052, 8);
+        expected.extend([7, 0, 0, 0, 0xaa, 0xbb, 0xcc, 0]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn set_custom_mode() {
+        let bytes = encode(Request::SetCustomMode { controller_idx: 5 });
+        assert_eq!(bytes, header(5, 1100, 0));
+    }
+
+    #[test]
+    fn update_mode() {
+        let mode = breathing_mode();
+        let bytes = encode(Request::UpdateMode {
+            controller_idx: 1,
+            mode_idx: 2,
+            mode: (&mode).into(),
+        });
+
+        let mut mode_bytes = vec![10, 0];
+        mode_bytes.extend(b"Breathing\0");
+        for x in [3u32, 0x41, 0, 4, 1, 2, 2, 0, 2] {
+            mode_bytes.extend(x.to_le_bytes());
+        }
+        mode_bytes.extend([2, 0, 0xff, 0x80, 0x00, 0, 0x00, 0x10, 0x20, 0]);
+
+        let len = 8 + mode_bytes.len() as u32;
+        let mut expected = header(1, 1101, len);
+        expected.extend(len.to_le_bytes());
+        expected.extend([2, 0, 0, 0]);
+        expected.extend(mode_bytes);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn save_mode_differs_only_by_packet_id() {
+        let mode = breathing_mode();
+        let update = encode(Request::UpdateMode {
+            controller_idx: 1,
+            mode_idx: 2,
+            mode: (&mode).into(),
+        });
+        let save = encode(Request::SaveMode {
+            controller_idx: 1,
+            mode_idx: 2,
+            mode: (&mode).into(),
+        });
+        assert_eq!(&save[8..12], &1102u32.to_le_bytes());
+        assert_eq!(save[..8], update[..8]);
+        assert_eq!(save[12..], update[12..]);
+    }
+
+    #[test]
+    fn mode_round_trip() {
+        let mode = breathing_mode();
+        let mut bytes = Vec::new();
+        unparse::mode(&mode, 0, &mut bytes);
+        let (rest, parsed) = parse::mode(0, &bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed, mode);
+    }
+
+    #[test]
+    fn mode_round_trip_v3() {
+        let mut mode = breathing_mode();
+        mode.flags |= ModeFlags::BRIGHTNESS;
+        mode.brightness_min = Some(0);
+        mode.brightness_max = Some(100);
+        mode.brightness = Some(80);
+
+        let mut bytes_v0 = Vec::new();
+        unparse::mode(&mode, 0, &mut bytes_v0);
+        let mut bytes_v3 = Vec::new();
+        unparse::mode(&mode, 3, &mut bytes_v3);
+        assert_eq!(bytes_v3.len(), bytes_v0.len() + 12);
+
+        let (rest, parsed) = parse::mode(3, &bytes_v3).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed, mode);
+    }
+
+    #[test]
+    fn parse_zone_segments_v4() {
+        let mut bytes = Vec::new();
+        unparse::string("Strip", &mut bytes);
+        for x in [1, 0, 60, 30] {
+            unparse::u32(x, &mut bytes);
+        }
+        unparse::u16(0, &mut bytes); // no matrix
+        unparse::u16(1, &mut bytes);
+        unparse::string("Left", &mut bytes);
+        for x in [1, 0, 10] {
+            unparse::u32(x, &mut bytes);
+        }
+
+        let (rest, zone) = parse::zone(4, &bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(zone.leds_count, 30);
+        assert_eq!(
+            zone.segments,
+            Some(vec![Segment {
+                name: "Left".into(),
+                ty: ZoneType::Linear,
+                start_idx: 0,
+                leds_count: 10,
+            }])
+        );
+
+        // Before version 4, the segments are not part of the zone
+        let (rest, zone) = parse::zone(3, &bytes).unwrap();
+        assert_eq!(rest.len(), 2 + 7 + 12);
+        assert_eq!(zone.segments, None);
+    }
+
+    fn read(bytes: &[u8]) -> Result<Response, ProtocolError> {
+        Response::read_from(&mut &bytes[..], 0)
+    }
+
+    #[test]
+    fn read_controller_count() {
+        let mut bytes = header(0, 0, 4);
+        bytes.extend([3, 0, 0, 0]);
+        assert!(matches!(read(&bytes), Ok(Response::ControllerCount(3))));
+    }
+
+    #[test]
+    fn read_bad_magic() {
+        let mut bytes = header(0, 0, 4);
+        bytes[0] = b'X';
+        bytes.extend([3, 0, 0, 0]);
+        assert!(matches!(read(&bytes), Err(ProtocolError::BadMagic)));
+    }
+
+    #[test]
+    fn read_unknown_packet_id() {
+        let bytes = header(0, 9999, 0);
+        assert!(matches!(
+            read(&bytes),
+            Err(ProtocolError::UnknownPacketId(9999))
+        ));
+    }
+
+    #[test]
+    fn read_trailing_data() {
+        let mut bytes = header(0, 40, 6);
+        bytes.extend([4, 0, 0, 0, 0xde, 0xad]);
+        assert!(matches!(read(&bytes), Err(ProtocolError::TrailingData(2))));
+    }
+
+    #[test]
+    fn read_truncated_payload() {
+        let mut bytes = header(0, 40, 2);
+        bytes.extend([4, 0]);
+        assert!(matches!(read(&bytes), Err(ProtocolError::Truncated)));
+    }
+
+    #[test]
+    fn read_truncated_stream() {
+        let bytes = header(0, 40, 4);
+        assert!(matches!(read(&bytes), Err(ProtocolError::Io(_))));
+    }
+
+    #[test]
+    fn parse_unknown_color_mode() {
+        let mut mode = breathing_mode();
+        mode.color_mode = ColorMode::Random;
+        let mut bytes = Vec::new();
+        unparse::mode(&mode, 0, &mut bytes);
+        // The color mode sits right before the color count and the two colors
+        let offset = bytes.len() - 4 - 2 - 8;
+        bytes[offset] = 42;
+        assert!(matches!(
+            parse::mode(0, &bytes),
+            Err(nom::Err::Failure(ProtocolError::UnknownEnumValue {
+                ty: "color mode",
+                value: 42
+            }))
+        ));
+    }
+
+    #[test]
+    fn parse_invalid_string() {
+        let mut bytes = Vec::new();
+        unparse::mode(&breathing_mode(), 0, &mut bytes);
+        bytes[2] = 0xff;
+        assert!(matches!(
+            parse::mode(0, &bytes),
+            Err(nom::Err::Failure(ProtocolError::InvalidString))
+        ));
+    }
+
+    fn keyboard() -> ControllerData {
+        ControllerData {
+            ty: ControllerType::Keyboard,
+            name: "Keyboard".into(),
+            vendor: Some("Vendor".into()),
+            description: "A keyboard".into(),
+            version: "2.1".into(),
+            serial: "1234".into(),
+            location: "HID: /dev/hidraw0".into(),
+            modes: vec![Mode {
+                brightness_min: Some(0),
+                brightness_max: Some(100),
+                brightness: Some(50),
+                ..breathing_mode()
+            }],
+            active_mode: 0,
+            zones: vec![Zone {
+                name: "Keys".into(),
+                ty: ZoneType::Matrix,
+                leds_min: 3,
+                leds_max: 3,
+                leds_count: 3,
+                matrix: Some(ZoneMatrix {
+                    height: 2,
+                    width: 2,
+                    data: vec![0, 1, 2, 0xFFFFFFFF],
+                }),
+                segments: Some(Vec::new()),
+            }],
+            leds: ["Esc", "F1", "F2"]
+                .into_iter()
+                .map(|name| Led {
+                    name: name.into(),
+                    value: 0,
+                })
+                .collect(),
+            colors: vec![Rgb(1, 2, 3), Rgb(4, 5, 6), Rgb(7, 8, 9)],
+        }
+    }
+
+    /// Drop the fields that do not exist in the given protocol version.
+    fn downgrade(mut c: ControllerData, version: u32) -> ControllerData {
+        if version < 1 {
+            c.vendor = None;
+        }
+        if version < 3 {
+            for m in &mut c.modes {
+                m.brightness_min = None;
+                m.brightness_max = None;
+                m.brightness = None;
+            }
+        }
+        if version < 4 {
+            for z in &mut c.zones {
+                z.segments = None;
+            }
+        }
+        c
+    }
+
+    #[test]
+    fn controller_data_round_trip() {
+        for version in 0..=PROTOCOL_VERSION {
+            let controller = downgrade(keyboard(), version);
+            let bytes = controller.encode(version);
+            assert_eq!(bytes[..4], (bytes.len() as u32).to_le_bytes());
+            assert_eq!(ControllerData::decode(&bytes, version).unwrap(), controller);
+        }
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let responses = [
+            Response::ControllerCount(3),
+            Response::ControllerData(downgrade(keyboard(), 4)),
+            Response::ProtocolVersion(4),
+            Response::DeviceListUpdated,
+            Response::ProfileList(vec!["Day".into(), "Night".into()]),
+            Response::PluginList(vec![Plugin {
+                name: "Effects".into(),
+                description: "Effects engine".into(),
+                version: "1.0".into(),
+                index: 1,
+                protocol_version: 3,
+            }]),
+            Response::PluginSpecific {
+                pkt_type: 7,
+                data: vec![1, 2, 3],
+            },
+        ];
+        for response in responses {
+            let mut bytes = Vec::new();
+            response.write_to(&mut bytes, 0, 4).unwrap();
+            let parsed = Response::read_from(&mut &bytes[..], 4).unwrap();
+            assert_eq!(format!("{parsed:?}"), format!("{response:?}"));
+        }
+    }
+
+    #[test]
+    fn profiles() {
+        let mut expected = header(0, 152, 6);
+        expected.extend(b"Night\0");
+        assert_eq!(encode(Request::LoadProfile("Night".into())), expected);
+
+        let mut bytes = Vec::new();
+        Response::ProfileList(vec!["Day".into()])
+            .write_to(&mut bytes, 0, 2)
+            .unwrap();
+        let mut expected = header(0, 150, 12);
+        expected.extend([12, 0, 0, 0, 1, 0, 4, 0]);
+        expected.extend(b"Day\0");
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn plugin_specific() {
+        let bytes = encode(Request::PluginSpecific {
+            plugin_idx: 2,
+            pkt_type: 7,
+            data: vec![0xaa, 0xbb].into(),
+        });
+        let mut expected = header(2, 201, 6);
+        expected.extend([7, 0, 0, 0, 0xaa, 0xbb]);
+        assert_eq!(bytes, expected);
+
+        // Packets that are unknown to this crate are reported, not fatal
+        let mut bytes = header(0, 9999, 2);
+        bytes.extend([1, 2]);
+        // The server starts the answer with the type of the request
+        bytes.extend(header(7, 201, 6));
+        bytes.extend([7, 0, 0, 0, 3, 4]);
+        let mut reader = &bytes[..];
+        assert!(matches!(
+            Response::read_from(&mut reader, 4),
+            Err(ProtocolError::UnknownPacketId(9999))
+        ));
+        assert!(matches!(
+            Response::read_from(&mut reader, 4),
+            Ok(Response::PluginSpecific { pkt_type: 7, data }) if data == [3, 4]
+        ));
+    }
+
+    #[test]
+    fn response_header() {
+        let mut bytes = Vec::new();
+        Response::ControllerCount(3)
+            .write_to(&mut bytes, 0, 0)
+            .unwrap();
+        let mut expected = header(0, 0, 4);
+        expected.extend([3, 0, 0, 0]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn header_layout() {
+        let bytes = encode(Request::ControllerData {
+            controller_idx: 0x0102_0304,
+        });
+        assert_eq!(
+            bytes,
+            [
+                b'O', b'R', b'G', b'B', 0x04, 0x03, 0x02, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00,
+            ]
+        );
+
+        let header_bytes = [
+            b'O', b'R', b'G', b'B', 0x01, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x01,
+            0x00, 0x00,
+        ];
+        let header = PacketHeader::decode(&header_bytes).unwrap();
+        assert_eq!(
+            (header.dev_idx, header.pkt_id, header.pkt_size),
+            (1, 100, 256)
+        );
+    }
+
+    #[test]
+    fn color_layout() {
+        let mut bytes = Vec::new();
+        unparse::color(Rgb(0x11, 0x22, 0x33), &mut bytes);
+        assert_eq!(bytes, [0x11, 0x22, 0x33, 0x00]);
+
+        let mut packet = header(0, 1052, 8);
+        packet.extend([0x07, 0x00, 0x00, 0x00, 0x11, 0x22, 0x33, 0x00]);
+        assert_eq!(
+            Request::read_from(&mut &packet[..], 0).unwrap(),
+            Request::UpdateSingleLed {
+                controller_idx: 0,
+                led_idx: 7,
+                color: Rgb(0x11, 0x22, 0x33),
+            }
+        );
+    }
+
+    #[test]
+    fn request_round_trip() {
+        for version in 0..=PROTOCOL_VERSION {
+            request_round_trip_version(version);
+        }
+    }
+
+    fn request_round_trip_version(version: u32) {
+        let mode = downgrade(keyboard(), version).modes.remove(0);
+        let requests = [
+            Request::ControllerCount,
+            Request::ControllerData { controller_idx: 2 },
+            Request::ProtocolVersion(4),
+            Request::SetClientName("Client".into()),
+            Request::ResizeZone {
+                controller_idx: 1,
+                zone_idx: 2,
+                new_size: 3,
+            },
+            Request::UpdateLeds {
+                controller_idx: 1,
+                colors: vec![Rgb(1, 2, 3), Rgb(4, 5, 6)].into(),
+            },
+            Request::UpdateZoneLeds {
+                controller_idx: 1,
+                zone_idx: 2,
+                colors: vec![Rgb(1, 2, 3)].into(),
+            },
+            Request::UpdateSingleLed {
+                controller_idx: 1,
+                led_idx: 2,
+                color: Rgb(1, 2, 3),
+            },
+            Request::SetCustomMode { controller_idx: 1 },
+            Request::UpdateMode {
+                controller_idx: 1,
+                mode_idx: 2,
+                mode: (&mode).into(),
+            },
+            Request::SaveMode {
+                controller_idx: 1,
+                mode_idx: 2,
+                mode: (&mode).into(),
+            },
+            Request::ProfileList,
+            Request::SaveProfile("Day".into()),
+            Request::LoadProfile("Night".into()),
+            Request::DeleteProfile("Day".into()),
+            Request::PluginList,
+            Request::PluginSpecific {
+                plugin_idx: 1,
+                pkt_type: 2,
+                data: vec![3, 4, 5].into(),
+            },
+        ];
+        for request in requests {
+            let mut bytes = Vec::new();
+            request.write_to(&mut bytes, version).unwrap();
+            let parsed = Request::read_from(&mut &bytes[..], version).unwrap();
+            assert_eq!(parsed, request);
+        }
     }
 }
@@ -0,0 +1,171 @@
+use super::connection::Connection;
+use super::protocol::{ControllerData, ControllerType, ProtocolError};
+
+/// Identifies a controller across rescans of the OpenRGB server, which may change the controller indices.
+///
+/// Two identical devices that report no serial and share a location cannot be told apart: they are matched in order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    pub ty: ControllerType,
+    pub name: String,
+    pub serial: String,
+    pub location: String,
+}
+
+impl DeviceId {
+    /// The identity of this controller.
+    pub fn of(controller: &ControllerData) -> DeviceId {
+        DeviceId {
+            ty: controller.ty,
+            name: controller.name.clone(),
+            serial: controller.serial.clone(),
+            location: controller.location.clone(),
+        }
+    }
+
+    /// Returns true if this is the identity of this controller.
+    pub fn matches(&self, controller: &ControllerData) -> bool {
+        self.ty == controller.ty
+            && self.name == controller.name
+            && self.serial == controller.serial
+            && self.location == controller.location
+    }
+
+    /// Returns the current index of the controller with this identity.
+    pub fn find_in(&self, controllers: &[ControllerData]) -> Option<u32> {
+        controllers
+            .iter()
+            .position(|c| self.matches(c))
+            .map(|p| p as u32)
+    }
+}
+
+impl From<&ControllerData> for DeviceId {
+    fn from(controller: &ControllerData) -> DeviceId {
+        DeviceId::of(controller)
+    }
+}
+
+/// A copy of the controllers of the OpenRGB server, which tells what changed whenever it is refreshed.
+///
+/// Controllers are matched between two refreshes by their [`DeviceId`], so that a controller keeps its identity when
+/// the controllers before it are plugged or unplugged.
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    controllers: Vec<ControllerData>,
+    /// Set when the server said that the controllers have changed, until they are downloaded.
+    outdated: bool,
+}
+
+/// The changes between two versions of the list of controllers.
+///
+/// Indices are controller indices: in the new list for the added, changed and moved controllers, and in the old list
+/// for the removed ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceDiff {
+    pub added: Vec<u32>,
+    pub removed: Vec<(u32, ControllerData)>,
+    /// Controllers whose description, modes, zones or LEDs have changed. Changes of colors are not reported.
+    pub changed: Vec<u32>,
+    /// Controllers whose index has changed, as pairs of old and new index.
+    pub moved: Vec<(u32, u32)>,
+}
+
+impl DeviceDiff {
+    /// Returns true if nothing has changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.moved.is_empty()
+    }
+}
+
+impl DeviceRegistry {
+    /// Create an empty registry. The first refresh reports every controller as added.
+    pub fn new() -> DeviceRegistry {
+        DeviceRegistry::default()
+    }
+
+    /// The current controllers, in the order of their indices.
+    pub fn controllers(&self) -> &[ControllerData] {
+        &self.controllers
+    }
+
+    /// Returns the controller at this index.
+    pub fn get(&self, controller_idx: u32) -> Option<&ControllerData> {
+        self.controllers.get(controller_idx as usize)
+    }
+
+    /// Returns the current index of the controller with this identity.
+    pub fn find(&self, id: &DeviceId) -> Option<u32> {
+        id.find_in(&self.controllers)
+    }
+
+    /// Download the controllers again if the server said that they have changed, and return what changed.
+    ///
+    /// Returns `None` if there was no notification since the last call. If the download fails, it is attempted again
+    /// by the next call.
+    pub fn poll(&mut self, serv: &mut Connection) -> Result<Option<DeviceDiff>, ProtocolError> {
+        if serv.devices_updated_reset() {
+            self.outdated = true;
+        }
+        if !self.outdated {
+            return Ok(None);
+        }
+        self.refresh(serv).map(Some)
+    }
+
+    /// Download the controllers again and return what changed.
+    pub fn refresh(&mut self, serv: &mut Connection) -> Result<DeviceDiff, ProtocolError> {
+        let controllers = serv.list_controllers()?;
+        self.outdated = false;
+        Ok(self.update(controllers))
+    }
+
+    /// Replace the controllers and return what changed.
+    pub fn update(&mut self, controllers: Vec<ControllerData>) -> DeviceDiff {
+        let old = std::mem::replace(&mut self.controllers, controllers);
+        diff(&old, &self.controllers)
+    }
+}
+
+fn same_layout(a: &ControllerData, b: &ControllerData) -> bool {
+    // The colors change every time a client updates the LEDs, which is not a change of the device
+    ControllerData {
+        colors: Vec::new(),
+        ..a.clone()
+    } == ControllerData {
+        colors: Vec::new(),
+        ..b.clone()
+    }
+}
+
+fn diff(old: &[ControllerData], new: &[ControllerData]) -> DeviceDiff {
+    let mut diff = DeviceDiff::default();
+    let mut matched = vec![false; old.len()];
+    for (new_idx, controller) in new.iter().enumerate() {
+        // Devices that share an identity are matched in order
+        let id = DeviceId::of(controller);
+        let old_idx = (0..old.len()).find(|&i| !matched[i] && id.matches(&old[i]));
+        match old_idx {
+            Some(old_idx) => {
+                matched[old_idx] = true;
+                if !same_layout(&old[old_idx], controller) {
+                    diff.changed.push(new_idx as u32);
+                }
+                if old_idx != new_idx {
+                    diff.moved.push((old_idx as u32, new_idx as u32));
+                }
+            }
+            None => diff.added.push(new_idx as u32),
+        }
+    }
+    diff.removed = old
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !matched[*i])
+        .map(|(i, c)| (i as u32, c.clone()))
+        .collect();
+    diff
+}
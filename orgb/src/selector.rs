@@ -0,0 +1,388 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::protocol::{ControllerData, ControllerType};
+
+/// Selects controllers, zones or LEDs by their properties.
+///
+/// A selector is a list of terms separated by whitespace, which must all match. Several selectors can be joined with
+/// commas, in which case anything that matches one of them is selected. A term is a key, an operator and a value:
+///
+/// - `type`, `name`, `vendor`, `serial` and `location` match a property of the controller.
+/// - `zone` and `led` restrict the selection to the LEDs of the matching zones, or to the matching LEDs.
+/// - `=` compares the whole value, while `~` matches a pattern where `*` stands for any text and `?` for any
+///   character. Patterns ignore the case.
+/// - Values that contain whitespace, commas or quotes are written between double quotes, with `\"` and `\\` escapes.
+///
+/// For example: `type=dram`, `name~"Corsair*"`, `type=cooler zone="Fan 1", type=ledstrip`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selector {
+    alternatives: Vec<Vec<Term>>,
+}
+
+/// The LEDs of one controller that are selected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub controller_idx: u32,
+    /// Indices of the LEDs in the controller, in increasing order.
+    pub leds: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Type,
+    Name,
+    Vendor,
+    Serial,
+    Location,
+    Zone,
+    Led,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Term {
+    Type(ControllerType),
+    Equals(Key, String),
+    Pattern(Key, String),
+}
+
+/// An error in the text of a selector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectorError {
+    /// Byte offset of the error in the text.
+    pub offset: usize,
+    pub kind: SelectorErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectorErrorKind {
+    /// The selector, or one of the alternatives, has no term.
+    Empty,
+    /// The key is not one of the known properties.
+    UnknownKey(String),
+    /// The key is not followed by `=` or `~`.
+    ExpectedOperator,
+    /// The operator is not followed by a value.
+    ExpectedValue,
+    /// A quoted value is missing its closing quote.
+    UnterminatedString,
+    /// The value of `type=` is not a controller type.
+    UnknownType(String),
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            SelectorErrorKind::Empty => write!(f, "empty selector")?,
+            SelectorErrorKind::UnknownKey(key) => write!(f, "unknown key {key:?}")?,
+            SelectorErrorKind::ExpectedOperator => write!(f, "expected `=` or `~`")?,
+            SelectorErrorKind::ExpectedValue => write!(f, "expected a value")?,
+            SelectorErrorKind::UnterminatedString => write!(f, "missing closing quote")?,
+            SelectorErrorKind::UnknownType(ty) => write!(f, "unknown controller type {ty:?}")?,
+        }
+        write!(f, " at offset {}", self.offset)
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+impl FromStr for Selector {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Selector, SelectorError> {
+        Parser { text: s, pos: 0 }.selector()
+    }
+}
+
+impl Selector {
+    /// Returns true if at least one LED of this controller is selected.
+    pub fn matches(&self, controller: &ControllerData) -> bool {
+        !self.select_leds(controller).is_empty()
+    }
+
+    /// Returns the selected LEDs, grouped by controller. Controllers without any selected LED are left out.
+    pub fn resolve(&self, controllers: &[ControllerData]) -> Vec<Selection> {
+        controllers
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, controller)| {
+                let leds = self.select_leds(controller);
+                (!leds.is_empty()).then_some(Selection {
+                    controller_idx: idx as u32,
+                    leds,
+                })
+            })
+            .collect()
+    }
+
+    fn select_leds(&self, controller: &ControllerData) -> Vec<u32> {
+        let mut selected = vec![false; controller.leds.len()];
+        for terms in &self.alternatives {
+            if !terms.iter().all(|t| t.matches_controller(controller)) {
+                continue;
+            }
+            for (led_idx, zone_idx) in led_zones(controller) {
+                if terms
+                    .iter()
+                    .all(|t| t.matches_led(controller, led_idx, zone_idx))
+                {
+                    selected[led_idx] = true;
+                }
+            }
+        }
+        (0..selected.len() as u32)
+            .filter(|&i| selected[i as usize])
+            .collect()
+    }
+}
+
+/// Returns the index of each LED, with the index of the zone that contains it.
+fn led_zones(controller: &ControllerData) -> impl Iterator<Item = (usize, Option<usize>)> + '_ {
+    let mut zone_of_led = Vec::with_capacity(controller.leds.len());
+    for (zone_idx, zone) in controller.zones.iter().enumerate() {
+        zone_of_led.extend(std::iter::repeat_n(
+            Some(zone_idx),
+            zone.leds_count as usize,
+        ));
+    }
+    zone_of_led.resize(controller.leds.len(), None);
+    zone_of_led.into_iter().enumerate()
+}
+
+impl Term {
+    fn matches_controller(&self, controller: &ControllerData) -> bool {
+        match self {
+            Term::Type(ty) => controller.ty == *ty,
+            Term::Equals(key, value) => match controller_property(controller, *key) {
+                Some(property) => property == *value,
+                None => true,
+            },
+            Term::Pattern(key, pattern) => match controller_property(controller, *key) {
+                Some(property) => glob_match(pattern, &property),
+                None => true,
+            },
+        }
+    }
+
+    fn matches_led(
+        &self,
+        controller: &ControllerData,
+        led_idx: usize,
+        zone_idx: Option<usize>,
+    ) -> bool {
+        let property = match self {
+            Term::Equals(key, _) | Term::Pattern(key, _) => match key {
+                Key::Zone => zone_idx.map(|i| controller.zones[i].name.as_str()),
+                Key::Led => Some(controller.leds[led_idx].name.as_str()),
+                _ => return true,
+            },
+            Term::Type(_) => return true,
+        };
+        match (self, property) {
+            (Term::Equals(_, value), Some(property)) => property == value,
+            (Term::Pattern(_, pattern), Some(property)) => glob_match(pattern, property),
+            _ => false,
+        }
+    }
+}
+
+/// The value of a property of the controller, or `None` if the key is about zones or LEDs.
+fn controller_property(controller: &ControllerData, key: Key) -> Option<String> {
+    match key {
+        Key::Type => Some(type_name(controller.ty).into()),
+        Key::Name => Some(controller.name.clone()),
+        Key::Vendor => Some(controller.vendor.clone().unwrap_or_default()),
+        Key::Serial => Some(controller.serial.clone()),
+        Key::Location => Some(controller.location.clone()),
+        Key::Zone | Key::Led => None,
+    }
+}
+
+const TYPE_NAMES: [(ControllerType, &str); 10] = [
+    (ControllerType::Motherboard, "motherboard"),
+    (ControllerType::Dram, "dram"),
+    (ControllerType::Gpu, "gpu"),
+    (ControllerType::Cooler, "cooler"),
+    (ControllerType::LedStrip, "ledstrip"),
+    (ControllerType::Keyboard, "keyboard"),
+    (ControllerType::Mouse, "mouse"),
+    (ControllerType::Mousemat, "mousemat"),
+    (ControllerType::Headset, "headset"),
+    (ControllerType::HeadsetStand, "headsetstand"),
+];
+
+fn type_name(ty: ControllerType) -> &'static str {
+    TYPE_NAMES.iter().find(|(t, _)| *t == ty).unwrap().1
+}
+
+/// Match a pattern where `*` stands for any text and `?` for any character, ignoring the case.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    // Backtrack to the last star when a character does not match
+    let (mut p, mut t) = (0, 0);
+    let mut last_star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                last_star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match last_star {
+                Some((star_p, star_t)) => {
+                    last_star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn selector(mut self) -> Result<Selector, SelectorError> {
+        let mut alternatives = Vec::new();
+        loop {
+            alternatives.push(self.terms()?);
+            if !self.eat(',') {
+                break;
+            }
+        }
+        Ok(Selector { alternatives })
+    }
+
+    fn terms(&mut self) -> Result<Vec<Term>, SelectorError> {
+        let mut terms = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None | Some(',') => break,
+                Some(_) => terms.push(self.term()?),
+            }
+        }
+        if terms.is_empty() {
+            return Err(self.error(SelectorErrorKind::Empty));
+        }
+        Ok(terms)
+    }
+
+    fn term(&mut self) -> Result<Term, SelectorError> {
+        let key_start = self.pos;
+        let key = match self.take_while(|c| !is_separator(c) && !matches!(c, '=' | '~' | '"')) {
+            "type" => Key::Type,
+            "name" => Key::Name,
+            "vendor" => Key::Vendor,
+            "serial" => Key::Serial,
+            "location" => Key::Location,
+            "zone" => Key::Zone,
+            "led" => Key::Led,
+            key => {
+                return Err(SelectorError {
+                    offset: key_start,
+                    kind: SelectorErrorKind::UnknownKey(key.into()),
+                })
+            }
+        };
+        let is_pattern = match self.peek() {
+            Some('=') => false,
+            Some('~') => true,
+            _ => return Err(self.error(SelectorErrorKind::ExpectedOperator)),
+        };
+        self.pos += 1;
+        let value_start = self.pos;
+        let value = self.value()?;
+        Ok(match (key, is_pattern) {
+            (Key::Type, false) => {
+                let lowercase = value.to_lowercase();
+                match TYPE_NAMES.iter().find(|(_, name)| *name == lowercase) {
+                    Some((ty, _)) => Term::Type(*ty),
+                    None => {
+                        return Err(SelectorError {
+                            offset: value_start,
+                            kind: SelectorErrorKind::UnknownType(value),
+                        })
+                    }
+                }
+            }
+            (key, false) => Term::Equals(key, value),
+            (key, true) => Term::Pattern(key, value),
+        })
+    }
+
+    fn value(&mut self) -> Result<String, SelectorError> {
+        if !self.eat('"') {
+            let value = self.take_while(|c| !is_separator(c) && c != '"');
+            if value.is_empty() {
+                return Err(self.error(SelectorErrorKind::ExpectedValue));
+            }
+            return Ok(value.into());
+        }
+        let start = self.pos - 1;
+        let mut value = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(value);
+                }
+                '\\' => match chars.next() {
+                    Some((_, escaped)) => value.push(escaped),
+                    None => break,
+                },
+                c => value.push(c),
+            }
+        }
+        Err(SelectorError {
+            offset: start,
+            kind: SelectorErrorKind::UnterminatedString,
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let eaten = self.peek() == Some(c);
+        if eaten {
+            self.pos += c.len_utf8();
+        }
+        eaten
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &str {
+        let start = self.pos;
+        let len = self.text[start..]
+            .find(|c| !f(c))
+            .unwrap_or(self.text.len() - start);
+        self.pos += len;
+        &self.text[start..self.pos]
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.take_while(char::is_whitespace);
+    }
+
+    fn error(&self, kind: SelectorErrorKind) -> SelectorError {
+        SelectorError {
+            offset: self.pos,
+            kind,
+        }
+    }
+}
+
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || c == ','
+}
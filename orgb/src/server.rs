@@ -0,0 +1,355 @@
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use super::protocol::{
+    ControllerData, Mode, ProtocolError, Request, Response, Rgb, PROTOCOL_VERSION,
+};
+
+/// A lighting device implemented in software, which a [`Server`] publishes to OpenRGB clients.
+///
+/// Only [`VirtualDevice::controller_data`] and [`VirtualDevice::update_leds`] are required. By default, updates of a
+/// zone or of a single LED are turned into an update of all the LEDs, and mode changes are ignored.
+pub trait VirtualDevice: Send {
+    /// Describe the device. This is called every time a client requests the controller data, so the colors should
+    /// reflect the current state of the device.
+    fn controller_data(&self) -> ControllerData;
+
+    /// Set the colors of all the LEDs.
+    fn update_leds(&mut self, colors: &[Rgb]);
+
+    /// Set the colors of the LEDs of one zone.
+    fn update_zone_leds(&mut self, zone_idx: u32, colors: &[Rgb]) {
+        let data = self.controller_data();
+        let Some(zone) = data.zones.get(zone_idx as usize) else {
+            log::warn!("Ignoring an update of unknown zone {zone_idx}");
+            return;
+        };
+        let start = data.zones[..zone_idx as usize]
+            .iter()
+            .map(|z| z.leds_count as usize)
+            .sum::<usize>();
+        let len = colors.len().min(zone.leds_count as usize);
+        let mut all_colors = data.colors;
+        match all_colors.get_mut(start..start + len) {
+            Some(zone_colors) => zone_colors.copy_from_slice(&colors[..len]),
+            None => {
+                log::warn!("Ignoring an update of zone {zone_idx}, which is out of range");
+                return;
+            }
+        }
+        self.update_leds(&all_colors);
+    }
+
+    /// Set the color of a single LED.
+    fn update_single_led(&mut self, led_idx: u32, color: Rgb) {
+        let mut colors = self.controller_data().colors;
+        match colors.get_mut(led_idx as usize) {
+            Some(c) => *c = color,
+            None => {
+                log::warn!("Ignoring an update of unknown LED {led_idx}");
+                return;
+            }
+        }
+        self.update_leds(&colors);
+    }
+
+    /// Change the number of LEDs of a resizable zone.
+    fn resize_zone(&mut self, _zone_idx: u32, _new_size: u32) {}
+
+    /// Switch to the mode that gives direct control over the LEDs.
+    fn set_custom_mode(&mut self) {}
+
+    /// Switch to a mode, with the given settings.
+    fn update_mode(&mut self, _mode_idx: u32, _mode: &Mode) {}
+
+    /// Switch to a mode and save it to the device, so that it survives a power cycle.
+    fn save_mode(&mut self, mode_idx: u32, mode: &Mode) {
+        self.update_mode(mode_idx, mode)
+    }
+}
+
+/// An OpenRGB server that publishes [`VirtualDevice`]s to the clients that connect to it.
+///
+/// Each client is served by its own thread. The server stops accepting clients when dropped.
+pub struct Server {
+    addr: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+}
+
+struct Shared {
+    devices: Vec<Box<dyn VirtualDevice>>,
+    clients: Vec<Arc<Client>>,
+    shutdown: bool,
+}
+
+/// A connected client. Its packets are written under its own lock, so that the notifications are not interleaved
+/// with the responses, without holding the lock on the devices.
+struct Client {
+    writer: Mutex<TcpStream>,
+    /// Another handle on the socket, to shut it down even while a write is blocked.
+    socket: TcpStream,
+}
+
+impl Client {
+    fn write(&self, response: &Response, dev_idx: u32, version: u32) -> io::Result<()> {
+        response.write_to(&mut *self.writer.lock().unwrap(), dev_idx, version)
+    }
+}
+
+impl Server {
+    /// Listen for clients on the given address.
+    ///
+    /// The OpenRGB SDK uses port 6742 by default.
+    pub fn start<A: ToSocketAddrs>(addr: A) -> io::Result<Server> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Mutex::new(Shared {
+            devices: Vec::new(),
+            clients: Vec::new(),
+            shutdown: false,
+        }));
+
+        // Launch the thread that accepts clients
+        let _accept_thread = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                for con in listener.incoming() {
+                    let con = match con {
+                        Ok(con) => con,
+                        Err(e) => {
+                            log::warn!("Could not accept a client: {e}");
+                            continue;
+                        }
+                    };
+                    let mut guard = shared.lock().unwrap();
+                    if guard.shutdown {
+                        return;
+                    }
+                    let client = match (con.try_clone(), con.try_clone()) {
+                        (Ok(writer), Ok(socket)) => Arc::new(Client {
+                            writer: Mutex::new(writer),
+                            socket,
+                        }),
+                        (Err(e), _) | (_, Err(e)) => {
+                            log::warn!("Could not clone the TcpStream: {e}");
+                            continue;
+                        }
+                    };
+                    guard.clients.push(Arc::clone(&client));
+                    let shared = Arc::clone(&shared);
+                    thread::spawn(move || serve_client(con, &client, &shared));
+                }
+            })
+        };
+
+        Ok(Server { addr, shared })
+    }
+
+    /// The address that the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Publish a device, and tell the clients that the list of devices has changed.
+    ///
+    /// Returns the index of the device, which is also its controller index.
+    pub fn add_device<D: VirtualDevice + 'static>(&self, device: D) -> u32 {
+        let mut shared = self.shared();
+        shared.devices.push(Box::new(device));
+        let device_idx = shared.devices.len() as u32 - 1;
+        self.notify(shared);
+        device_idx
+    }
+
+    /// Stop publishing a device, and tell the clients that the list of devices has changed.
+    ///
+    /// The devices that come after it are shifted down by one index.
+    pub fn remove_device(&self, device_idx: u32) -> Option<Box<dyn VirtualDevice>> {
+        let mut shared = self.shared();
+        if device_idx as usize >= shared.devices.len() {
+            return None;
+        }
+        let device = shared.devices.remove(device_idx as usize);
+        self.notify(shared);
+        Some(device)
+    }
+
+    /// Tell the clients that the list of devices has changed, for example because a device changed its layout.
+    pub fn notify_device_list_updated(&self) {
+        self.notify(self.shared());
+    }
+
+    /// Tell the clients that the list of devices has changed, once the lock on the devices is released so that a slow
+    /// client does not hold up the others. The clients that cannot be written to are forgotten.
+    fn notify(&self, shared: MutexGuard<'_, Shared>) {
+        let clients = shared.clients.clone();
+        drop(shared);
+        // The layout of this notification does not depend on the protocol version
+        let gone: Vec<_> = clients
+            .into_iter()
+            .filter(|client| client.write(&Response::DeviceListUpdated, 0, 0).is_err())
+            .collect();
+        if !gone.is_empty() {
+            let mut shared = self.shared();
+            shared
+                .clients
+                .retain(|client| !gone.iter().any(|g| Arc::ptr_eq(client, g)));
+        }
+    }
+
+    fn shared(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let mut shared = self.shared();
+        shared.shutdown = true;
+        for client in shared.clients.drain(..) {
+            let _ = client.socket.shutdown(Shutdown::Both);
+        }
+        drop(shared);
+        // Wake up the accepting thread so that it notices the shutdown
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+/// Answer the requests of one client until it disconnects.
+fn serve_client(mut con: TcpStream, client: &Client, shared: &Mutex<Shared>) {
+    let peer = con
+        .peer_addr()
+        .map_or_else(|_| "unknown".into(), |a| a.to_string());
+    log::info!("Client {peer} connected");
+
+    let mut version = 0;
+    loop {
+        let request = match Request::read_from(&mut con, version) {
+            Ok(request) => request,
+            Err(e @ (ProtocolError::Io(_) | ProtocolError::BadMagic)) => {
+                log::info!("Client {peer} disconnected: {e}");
+                return;
+            }
+            // The whole packet has been consumed, so the stream is still in sync
+            Err(e) => {
+                log::warn!("Skipping a malformed packet from client {peer}: {e}");
+                continue;
+            }
+        };
+
+        let mut shared = shared.lock().unwrap();
+        let devices = &mut shared.devices;
+        let response = match request {
+            Request::ControllerCount => Some((0, Response::ControllerCount(devices.len() as u32))),
+            Request::ControllerData { controller_idx } => {
+                devices.get(controller_idx as usize).map(|d| {
+                    (
+                        controller_idx,
+                        Response::ControllerData(d.controller_data()),
+                    )
+                })
+            }
+            Request::ProtocolVersion(v) => {
+                version = v.min(PROTOCOL_VERSION);
+                Some((0, Response::ProtocolVersion(PROTOCOL_VERSION)))
+            }
+            Request::SetClientName(name) => {
+                log::info!("Client {peer} is named {name:?}");
+                None
+            }
+            Request::ResizeZone {
+                controller_idx,
+                zone_idx,
+                new_size,
+            } => {
+                with_device(devices, controller_idx, |d| {
+                    d.resize_zone(zone_idx, new_size)
+                });
+                None
+            }
+            Request::UpdateLeds {
+                controller_idx,
+                colors,
+            } => {
+                with_device(devices, controller_idx, |d| d.update_leds(&colors));
+                None
+            }
+            Request::UpdateZoneLeds {
+                controller_idx,
+                zone_idx,
+                colors,
+            } => {
+                with_device(devices, controller_idx, |d| {
+                    d.update_zone_leds(zone_idx, &colors)
+                });
+                None
+            }
+            Request::UpdateSingleLed {
+                controller_idx,
+                led_idx,
+                color,
+            } => {
+                with_device(devices, controller_idx, |d| {
+                    d.update_single_led(led_idx, color)
+                });
+                None
+            }
+            Request::SetCustomMode { controller_idx } => {
+                with_device(devices, controller_idx, |d| d.set_custom_mode());
+                None
+            }
+            Request::UpdateMode {
+                controller_idx,
+                mode_idx,
+                mode,
+            } => {
+                with_device(devices, controller_idx, |d| d.update_mode(mode_idx, &mode));
+                None
+            }
+            Request::SaveMode {
+                controller_idx,
+                mode_idx,
+                mode,
+            } => {
+                with_device(devices, controller_idx, |d| d.save_mode(mode_idx, &mode));
+                None
+            }
+            // Virtual devices have no profiles, but the client still expects an answer
+            Request::ProfileList => Some((0, Response::ProfileList(Vec::new()))),
+            Request::SaveProfile(name)
+            | Request::LoadProfile(name)
+            | Request::DeleteProfile(name) => {
+                log::info!("Ignoring a request from client {peer} about profile {name:?}");
+                None
+            }
+            // Neither do they have plugins
+            Request::PluginList => Some((0, Response::PluginList(Vec::new()))),
+            Request::PluginSpecific { plugin_idx, .. } => {
+                log::warn!("Ignoring a request from client {peer} to unknown plugin {plugin_idx}");
+                None
+            }
+        };
+
+        drop(shared);
+
+        if let Some((dev_idx, response)) = response {
+            if let Err(e) = client.write(&response, dev_idx, version) {
+                log::info!("Client {peer} disconnected: {e}");
+                return;
+            }
+        }
+    }
+}
+
+fn with_device(
+    devices: &mut [Box<dyn VirtualDevice>],
+    controller_idx: u32,
+    f: impl FnOnce(&mut dyn VirtualDevice),
+) {
+    match devices.get_mut(controller_idx as usize) {
+        Some(device) => f(device.as_mut()),
+        None => log::warn!("Ignoring a request for unknown controller {controller_idx}"),
+    }
+}
@@ -0,0 +1,596 @@
+use orgb::{
+    mock_controller, Connection, ControllerType, MockServer, Request, Response, Rgb,
+    PROTOCOL_VERSION,
+};
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn fixtures() -> Vec<orgb::ControllerData> {
+    vec![
+        mock_controller(ControllerType::Dram, "DRAM", 5),
+        mock_controller(ControllerType::LedStrip, "Strip", 60),
+    ]
+}
+
+/// Poll a condition until it becomes true, or panic after the timeout.
+fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn list_controllers() {
+    let server = MockServer::start(fixtures());
+    let mut serv = Connection::start(server.addr());
+    assert_eq!(serv.negotiate_protocol_version().unwrap(), PROTOCOL_VERSION);
+    assert_eq!(serv.list_controllers().unwrap(), fixtures());
+}
+
+#[test]
+fn negotiate_older_protocol_version() {
+    let server = MockServer::start(fixtures());
+    server.set_protocol_version(1);
+    let mut serv = Connection::start(server.addr());
+    assert_eq!(serv.negotiate_protocol_version().unwrap(), 1);
+
+    // Fields that require a newer version are absent
+    let controller = serv.controller_data(0).unwrap();
+    assert_eq!(controller.vendor.as_deref(), Some("Mock"));
+    assert_eq!(controller.modes[0].brightness, None);
+    assert_eq!(controller.zones[0].segments, None);
+}
+
+#[test]
+fn negotiate_at_most_the_supported_protocol_version() {
+    let server = MockServer::start(fixtures());
+    server.set_protocol_version(7);
+    let mut serv = Connection::start(server.addr());
+    assert_eq!(serv.request_protocol_version(9).unwrap(), PROTOCOL_VERSION);
+    assert_eq!(serv.protocol_version(), PROTOCOL_VERSION);
+    assert_eq!(serv.list_controllers().unwrap(), fixtures());
+}
+
+#[test]
+fn fall_back_to_protocol_version_0() {
+    let server = MockServer::start(fixtures());
+    server.set_protocol_version(0);
+    let mut serv = Connection::start(server.addr());
+    assert_eq!(serv.negotiate_protocol_version().unwrap(), 0);
+    assert_eq!(serv.protocol_version(), 0);
+    assert_eq!(serv.controller_data(0).unwrap().vendor, None);
+}
+
+#[test]
+fn stay_at_protocol_version_0_when_the_server_hangs() {
+    let server = MockServer::start(fixtures());
+    server.set_unresponsive(true);
+    let mut serv = Connection::start(server.addr());
+    assert_eq!(serv.negotiate_protocol_version().unwrap(), 0);
+
+    // Both sides keep encoding at version 0
+    server.set_unresponsive(false);
+    assert_eq!(serv.controller_data(0).unwrap().vendor, None);
+}
+
+#[test]
+fn record_requests() {
+    let server = MockServer::start(fixtures());
+    let mut serv = Connection::start(server.addr());
+    serv.set_client_name("Test client").unwrap();
+    let colors = [Rgb(1, 2, 3); 5];
+    serv.update_leds(0, &colors).unwrap();
+
+    let requests = server.wait_for_requests(2, TIMEOUT);
+    assert_eq!(
+        requests,
+        vec![
+            Request::SetClientName("Test client".into()),
+            Request::UpdateLeds {
+                controller_idx: 0,
+                colors: colors[..].into(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn device_list_updated() {
+    let server = MockServer::start(fixtures());
+    let mut serv = Connection::start(server.addr());
+    assert!(serv.devices_updated_reset());
+    assert_eq!(serv.controller_count().unwrap(), 2);
+    assert!(!serv.devices_updated_reset());
+
+    server.set_controllers(vec![mock_controller(ControllerType::Gpu, "GPU", 1)]);
+    server.notify_device_list_updated();
+    wait_until(|| serv.devices_updated_reset());
+    assert_eq!(serv.controller_count().unwrap(), 1);
+}
+
+#[test]
+fn reconnect_after_disconnection() {
+    let server = MockServer::start(fixtures());
+    let (mut serv, version) = Connection::builder()
+        .address(server.addr())
+        .unwrap()
+        .client_name("Test client")
+        .reconnect(true)
+        .connect()
+        .unwrap();
+    assert_eq!(version, PROTOCOL_VERSION);
+    assert!(serv.devices_updated_reset());
+
+    server.disconnect_clients();
+    wait_until(|| !serv.is_connected());
+    wait_until(|| serv.is_connected());
+    assert!(serv.devices_updated_reset());
+    assert_eq!(
+        serv.state_changes().collect::<Vec<_>>(),
+        vec![
+            orgb::ConnectionState::Disconnected,
+            orgb::ConnectionState::Connected
+        ]
+    );
+
+    // The handshake has been performed again
+    let requests = server.wait_for_requests(4, TIMEOUT);
+    assert_eq!(
+        requests[requests.len() - 2..],
+        [
+            Request::SetClientName("Test client".into()),
+            Request::ProtocolVersion(PROTOCOL_VERSION),
+        ]
+    );
+    assert_eq!(serv.list_controllers().unwrap(), fixtures());
+}
+
+#[test]
+fn async_connection() {
+    use tokio_stream::StreamExt;
+
+    let server = MockServer::start(fixtures());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let (serv, mut notifications) =
+            orgb::AsyncConnection::connect(server.addr()).await.unwrap();
+        assert_eq!(
+            serv.negotiate_protocol_version().await.unwrap(),
+            PROTOCOL_VERSION
+        );
+        assert_eq!(serv.list_controllers().await.unwrap(), fixtures());
+
+        server.notify_device_list_updated();
+        assert_eq!(
+            notifications.next().await,
+            Some(orgb::Notification::DeviceListUpdated)
+        );
+    });
+}
+
+#[test]
+fn async_fall_back_to_protocol_version_0() {
+    let server = MockServer::start(fixtures());
+    server.set_protocol_version(0);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let (serv, _) = orgb::AsyncConnection::connect(server.addr()).await.unwrap();
+        assert_eq!(serv.negotiate_protocol_version().await.unwrap(), 0);
+        assert_eq!(serv.controller_data(0).await.unwrap().vendor, None);
+    });
+}
+
+#[test]
+fn async_negotiate_at_most_the_supported_protocol_version() {
+    let server = MockServer::start(fixtures());
+    server.set_protocol_version(7);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let (serv, _) = orgb::AsyncConnection::connect(server.addr()).await.unwrap();
+        assert_eq!(
+            serv.request_protocol_version(9).await.unwrap(),
+            PROTOCOL_VERSION
+        );
+        assert_eq!(serv.list_controllers().await.unwrap(), fixtures());
+    });
+}
+
+#[test]
+fn async_requests_wait_for_their_own_response() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut con, _) = listener.accept().unwrap();
+        // Answer the second request first, then the first one, which has been dropped
+        thread::sleep(Duration::from_millis(200));
+        let controller = mock_controller(ControllerType::Gpu, "GPU", 1);
+        Response::ControllerData(controller)
+            .write_to(&mut con, 1, 0)
+            .unwrap();
+        Response::ControllerCount(1)
+            .write_to(&mut con, 0, 0)
+            .unwrap();
+        Response::ControllerCount(2)
+            .write_to(&mut con, 0, 0)
+            .unwrap();
+        con
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let (serv, _) = orgb::AsyncConnection::connect(addr).await.unwrap();
+        let dropped =
+            tokio::time::timeout(Duration::from_millis(10), serv.controller_count()).await;
+        assert!(dropped.is_err());
+        let (count, controller) = tokio::join!(serv.controller_count(), serv.controller_data(1));
+        assert_eq!(count.unwrap(), 2);
+        assert_eq!(controller.unwrap().name, "GPU");
+        assert!(matches!(
+            serv.request(Request::SetCustomMode { controller_idx: 0 })
+                .await,
+            Err(orgb::ProtocolError::NoResponse)
+        ));
+    });
+    let _con = server.join().unwrap();
+}
+
+#[test]
+fn async_notifications_are_bounded() {
+    use tokio_stream::StreamExt;
+
+    let server = MockServer::start(fixtures());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let (serv, mut notifications) =
+            orgb::AsyncConnection::connect(server.addr()).await.unwrap();
+        // Make sure that the server knows the client
+        serv.controller_count().await.unwrap();
+        for _ in 0..20 {
+            server.notify_device_list_updated();
+        }
+        // The notifications have all been received once the response arrives
+        assert_eq!(serv.controller_count().await.unwrap(), 2);
+        let mut count = 0;
+        while let Ok(Some(_)) =
+            tokio::time::timeout(Duration::from_millis(10), notifications.next()).await
+        {
+            count += 1;
+        }
+        assert_eq!(count, 16);
+    });
+}
+
+/// Answer the plugin-specific requests as the OpenRGB server does, with their type in place of the device index and
+/// with their data reversed, in the reverse order of the requests. The other requests are ignored.
+fn answer_plugin_requests(mut con: TcpStream, count: usize) -> TcpStream {
+    let mut requests = Vec::new();
+    while requests.len() < count {
+        if let Request::PluginSpecific { pkt_type, data, .. } =
+            Request::read_from(&mut con, PROTOCOL_VERSION).unwrap()
+        {
+            requests.push((pkt_type, data.into_owned()));
+        }
+    }
+    for (pkt_type, mut data) in requests.into_iter().rev() {
+        data.reverse();
+        Response::PluginSpecific { pkt_type, data }
+            .write_to(&mut con, pkt_type, PROTOCOL_VERSION)
+            .unwrap();
+    }
+    con
+}
+
+#[test]
+fn plugin_requests() {
+    let (addr, server) = hanging_server();
+    let (mut serv, _) = Connection::builder()
+        .address(addr)
+        .unwrap()
+        .connect()
+        .unwrap();
+    let con = server.join().unwrap();
+    let server = thread::spawn(move || answer_plugin_requests(con, 1));
+    assert_eq!(serv.plugin_request(1, 7, &[1, 2]).unwrap(), [2, 1]);
+    let _con = server.join().unwrap();
+}
+
+#[test]
+fn async_plugin_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || answer_plugin_requests(listener.accept().unwrap().0, 2));
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let (serv, _) = orgb::AsyncConnection::connect(addr).await.unwrap();
+        // The answers to two requests to the same plugin are told apart by their type
+        let both = async {
+            tokio::join!(
+                serv.plugin_request(1, 7, &[1, 2]),
+                serv.plugin_request(1, 8, &[3, 4])
+            )
+        };
+        let (first, second) = tokio::time::timeout(TIMEOUT, both).await.unwrap();
+        assert_eq!(first.unwrap(), [2, 1]);
+        assert_eq!(second.unwrap(), [4, 3]);
+    });
+    let _con = server.join().unwrap();
+}
+
+#[test]
+fn manage_profiles() {
+    let server = MockServer::start(fixtures());
+    server.set_profiles(vec!["Day".into()]);
+    let mut serv = Connection::start(server.addr());
+    serv.negotiate_protocol_version().unwrap();
+    assert_eq!(serv.profile_list().unwrap(), ["Day"]);
+
+    serv.save_profile("Night").unwrap();
+    serv.delete_profile("Day").unwrap();
+    serv.load_profile("Night").unwrap();
+    assert_eq!(serv.profile_list().unwrap(), ["Night"]);
+    assert_eq!(
+        server.take_requests()[4..],
+        [Request::LoadProfile("Night".into()), Request::ProfileList]
+    );
+}
+
+#[test]
+fn ignore_responses_to_no_request() {
+    let server = MockServer::start(fixtures());
+    let mut serv = Connection::start(server.addr());
+    serv.negotiate_protocol_version().unwrap();
+    // Nobody waits for this response
+    serv.send(Request::ControllerCount).unwrap();
+    assert_eq!(serv.controller_data(1).unwrap(), fixtures()[1]);
+    assert_eq!(serv.controller_count().unwrap(), 2);
+
+    assert!(matches!(
+        serv.request(Request::SetCustomMode { controller_idx: 0 }),
+        Err(orgb::ProtocolError::NoResponse)
+    ));
+}
+
+/// Accept one client and complete its handshake, then return the stream to play a server that hangs.
+fn hanging_server() -> (SocketAddr, thread::JoinHandle<TcpStream>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut con, _) = listener.accept().unwrap();
+        Response::ProtocolVersion(PROTOCOL_VERSION)
+            .write_to(&mut con, 0, 0)
+            .unwrap();
+        con
+    });
+    (addr, server)
+}
+
+#[test]
+fn handshake_on_connect() {
+    let server = MockServer::start(fixtures());
+    let (mut serv, version) = Connection::builder()
+        .address(server.addr())
+        .unwrap()
+        .client_name("Test client")
+        .protocol_version(2)
+        .connect()
+        .unwrap();
+    assert_eq!(version, 2);
+    assert_eq!(serv.protocol_version(), 2);
+    assert_eq!(
+        server.take_requests(),
+        [
+            Request::SetClientName("Test client".into()),
+            Request::ProtocolVersion(2)
+        ]
+    );
+    assert_eq!(serv.controller_data(0).unwrap().zones[0].segments, None);
+}
+
+#[test]
+fn time_out_when_the_server_does_not_answer() {
+    let (addr, server) = hanging_server();
+    let (mut serv, _) = Connection::builder()
+        .address(addr)
+        .unwrap()
+        .request_timeout(Duration::from_millis(200))
+        .connect()
+        .unwrap();
+    let _con = server.join().unwrap();
+    assert!(matches!(
+        serv.controller_count(),
+        Err(orgb::ProtocolError::Timeout)
+    ));
+    assert!(matches!(
+        serv.request_timeout(Request::ControllerCount, Duration::from_millis(10)),
+        Err(orgb::ProtocolError::Timeout)
+    ));
+}
+
+/// Returns true if the request failed because the link to the server has been lost.
+fn is_disconnected<T>(result: Result<T, orgb::ProtocolError>) -> bool {
+    matches!(result, Err(orgb::ProtocolError::Io(e)) if e.kind() == ErrorKind::ConnectionAborted)
+}
+
+#[test]
+fn fail_requests_when_the_link_is_lost() {
+    let (addr, server) = hanging_server();
+    let (mut serv, _) = Connection::builder()
+        .address(addr)
+        .unwrap()
+        .connect()
+        .unwrap();
+    let con = server.join().unwrap();
+
+    let waiting = thread::spawn(move || {
+        let result = serv.request_timeout(Request::ControllerCount, TIMEOUT);
+        (serv, result)
+    });
+    thread::sleep(Duration::from_millis(100));
+    drop(con);
+    let (mut serv, result) = waiting.join().unwrap();
+    assert!(is_disconnected(result));
+    assert!(!serv.is_connected());
+    assert!(is_disconnected(serv.controller_count()));
+}
+
+#[test]
+fn ignore_late_responses() {
+    let (addr, server) = hanging_server();
+    let (mut serv, _) = Connection::builder()
+        .address(addr)
+        .unwrap()
+        .connect()
+        .unwrap();
+    let mut con = server.join().unwrap();
+    assert!(matches!(
+        serv.request_timeout(Request::ControllerCount, Duration::from_millis(10)),
+        Err(orgb::ProtocolError::Timeout)
+    ));
+
+    // The response to the first request arrives while the second one is waiting
+    let server = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        for count in [1, 2] {
+            Response::ControllerCount(count)
+                .write_to(&mut con, 0, PROTOCOL_VERSION)
+                .unwrap();
+        }
+        con
+    });
+    assert_eq!(serv.controller_count().unwrap(), 2);
+    let _con = server.join().unwrap();
+}
+
+#[test]
+fn forget_requests_that_are_never_answered() {
+    let (addr, server) = hanging_server();
+    let (mut serv, _) = Connection::builder()
+        .address(addr)
+        .unwrap()
+        .request_timeout(Duration::from_millis(100))
+        .connect()
+        .unwrap();
+    let con = server.join().unwrap();
+    assert!(matches!(
+        serv.controller_count(),
+        Err(orgb::ProtocolError::Timeout)
+    ));
+
+    // The server never answers the first request, so the response goes to the second one once the first is given up
+    thread::sleep(Duration::from_millis(150));
+    let answer = |mut con: TcpStream, count| {
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            Response::ControllerCount(count)
+                .write_to(&mut con, 0, PROTOCOL_VERSION)
+                .unwrap();
+            con
+        })
+    };
+    let server = answer(con, 2);
+    assert_eq!(serv.controller_count().unwrap(), 2);
+    let con = server.join().unwrap();
+
+    // Before that, the response to the second request is taken by the first one, but the third request gets its own
+    assert!(matches!(
+        serv.controller_count(),
+        Err(orgb::ProtocolError::Timeout)
+    ));
+    let server = answer(con, 3);
+    assert!(matches!(
+        serv.controller_count(),
+        Err(orgb::ProtocolError::Timeout)
+    ));
+    let con = server.join().unwrap();
+    let server = answer(con, 4);
+    assert_eq!(serv.controller_count().unwrap(), 4);
+    let _con = server.join().unwrap();
+}
+
+#[test]
+fn handshake_with_a_server_at_protocol_version_0() {
+    let server = MockServer::start(fixtures());
+    server.set_protocol_version(0);
+    let (mut serv, version) = Connection::builder()
+        .address(server.addr())
+        .unwrap()
+        .connection_tries(1)
+        .request_timeout(Duration::from_millis(200))
+        .connect()
+        .unwrap();
+    assert_eq!(version, 0);
+    assert_eq!(serv.controller_data(0).unwrap().vendor, None);
+}
+
+#[test]
+fn fail_when_the_server_stalls_in_a_packet() {
+    let (addr, server) = hanging_server();
+    let (mut serv, _) = Connection::builder()
+        .address(addr)
+        .unwrap()
+        .read_timeout(Duration::from_millis(100))
+        .connect()
+        .unwrap();
+    let mut con = server.join().unwrap();
+
+    // Staying silent between packets is fine
+    thread::sleep(Duration::from_millis(300));
+    con.write_all(b"ORGB").unwrap();
+    assert!(matches!(
+        serv.request_timeout(Request::ControllerCount, TIMEOUT),
+        Err(orgb::ProtocolError::Io(_))
+    ));
+}
+
+#[test]
+fn give_up_connecting() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let result = Connection::builder()
+        .address(addr)
+        .unwrap()
+        .connection_tries(2)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .connect_timeout(TIMEOUT)
+        .connect();
+    assert!(matches!(result, Err(orgb::ProtocolError::Io(_))));
+}
+
+#[test]
+fn reject_invalid_addresses() {
+    assert!(Connection::builder().address("not an address").is_err());
+    assert!(Connection::builder().address(("127.0.0.1", 6742)).is_ok());
+}
+
+#[test]
+#[should_panic(expected = "The request timeout must not be zero")]
+fn reject_a_zero_request_timeout() {
+    let _ = Connection::builder().request_timeout(Duration::ZERO);
+}
@@ -0,0 +1,114 @@
+use orgb::{
+    mock_controller, Connection, ControllerType, DeviceDiff, DeviceId, DeviceRegistry, MockServer,
+    ProtocolError, Rgb,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn dram(name: &str) -> orgb::ControllerData {
+    mock_controller(ControllerType::Dram, name, 5)
+}
+
+#[test]
+fn diff_controllers() {
+    let mut registry = DeviceRegistry::new();
+    let diff = registry.update(vec![dram("A"), dram("B")]);
+    assert_eq!(diff.added, [0, 1]);
+
+    // Colors alone are not a change
+    let mut a = dram("A");
+    a.colors[0] = Rgb(1, 2, 3);
+    assert!(registry.update(vec![a.clone(), dram("B")]).is_empty());
+
+    // A is unplugged, C is plugged, B moves and is resized
+    let mut b = dram("B");
+    b.zones[0].leds_count = 6;
+    let diff = registry.update(vec![b, dram("C")]);
+    assert_eq!(
+        diff,
+        DeviceDiff {
+            added: vec![1],
+            removed: vec![(0, a)],
+            changed: vec![0],
+            moved: vec![(1, 0)],
+        }
+    );
+    assert_eq!(registry.get(1).unwrap().name, "C");
+}
+
+#[test]
+fn find_devices_by_identity() {
+    let mut registry = DeviceRegistry::new();
+    registry.update(vec![dram("A"), dram("B")]);
+    let b = DeviceId::of(registry.get(1).unwrap());
+    assert_eq!(registry.find(&b), Some(1));
+
+    registry.update(vec![dram("B")]);
+    assert_eq!(registry.find(&b), Some(0));
+
+    // Same serial and location, but another kind of device
+    let mut gpu = dram("B");
+    gpu.ty = ControllerType::Gpu;
+    registry.update(vec![gpu]);
+    assert_eq!(registry.find(&b), None);
+}
+
+#[test]
+fn refresh_on_notification() {
+    let server = MockServer::start(vec![dram("A")]);
+    let mut serv = Connection::start(server.addr());
+    let mut registry = DeviceRegistry::new();
+    assert_eq!(registry.poll(&mut serv).unwrap().unwrap().added, [0]);
+    assert_eq!(registry.poll(&mut serv).unwrap(), None);
+
+    server.set_controllers(vec![dram("A"), dram("B")]);
+    server.notify_device_list_updated();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let diff = loop {
+        if let Some(diff) = registry.poll(&mut serv).unwrap() {
+            break diff;
+        }
+        assert!(Instant::now() < deadline, "Timed out");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(diff.added, [1]);
+    assert_eq!(registry.controllers().len(), 2);
+}
+
+#[test]
+fn refresh_again_after_a_failed_poll() {
+    let server = MockServer::start(vec![dram("A")]);
+    let (mut serv, _) = Connection::builder()
+        .address(server.addr())
+        .unwrap()
+        .request_timeout(Duration::from_millis(100))
+        .connect()
+        .unwrap();
+    let mut registry = DeviceRegistry::new();
+    assert_eq!(registry.poll(&mut serv).unwrap().unwrap().added, [0]);
+
+    // The server hangs when the controllers are requested after the notification
+    server.set_unresponsive(true);
+    server.set_controllers(vec![dram("A"), dram("B")]);
+    server.notify_device_list_updated();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match registry.poll(&mut serv) {
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(ProtocolError::Timeout) => break,
+            other => panic!("Expected a timeout, got {other:?}"),
+        }
+        assert!(Instant::now() < deadline, "Timed out");
+    }
+
+    // There is no other notification, but the controllers are still requested again
+    server.set_unresponsive(false);
+    let diff = loop {
+        if let Ok(Some(diff)) = registry.poll(&mut serv) {
+            break diff;
+        }
+        assert!(Instant::now() < deadline, "Timed out");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(diff.added, [1]);
+}
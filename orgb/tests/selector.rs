@@ -0,0 +1,86 @@
+use orgb::{
+    mock_controller, ControllerData, ControllerType, Selection, Selector, SelectorErrorKind,
+};
+
+fn rig() -> Vec<ControllerData> {
+    let mut cooler = mock_controller(ControllerType::Cooler, "Corsair Commander", 6);
+    let mut fan_2 = cooler.zones[0].clone();
+    cooler.zones[0].name = "Fan 1".into();
+    cooler.zones[0].leds_count = 4;
+    fan_2.name = "Fan 2".into();
+    fan_2.leds_count = 2;
+    cooler.zones.push(fan_2);
+    vec![
+        mock_controller(ControllerType::Dram, "Corsair Vengeance", 5),
+        mock_controller(ControllerType::Gpu, "GPU", 2),
+        mock_controller(ControllerType::Dram, "Kingston Fury", 5),
+        cooler,
+    ]
+}
+
+fn resolve(selector: &str) -> Vec<Selection> {
+    selector.parse::<Selector>().unwrap().resolve(&rig())
+}
+
+fn controllers(selector: &str) -> Vec<u32> {
+    resolve(selector).iter().map(|s| s.controller_idx).collect()
+}
+
+#[test]
+fn select_controllers() {
+    assert_eq!(controllers("type=dram"), [0, 2]);
+    assert_eq!(controllers("type=DRAM name~corsair*"), [0]);
+    assert_eq!(controllers(r#"name~"* Fury""#), [2]);
+    assert_eq!(controllers("name=gpu"), [] as [u32; 0]);
+    assert_eq!(
+        controllers(r#"type=gpu, serial="MOCK-Kingston Fury""#),
+        [1, 2]
+    );
+    assert_eq!(controllers("location~mock://?PU"), [1]);
+    assert_eq!(resolve("type=gpu")[0].leds, [0, 1]);
+}
+
+#[test]
+fn select_zones_and_leds() {
+    assert_eq!(
+        resolve(r#"zone="Fan 1""#),
+        [Selection {
+            controller_idx: 3,
+            leds: vec![0, 1, 2, 3]
+        }]
+    );
+    assert_eq!(resolve("type=cooler zone~*2")[0].leds, [4, 5]);
+    assert_eq!(
+        resolve("zone~*2, zone=\"Fan 1\"")[0].leds,
+        [0, 1, 2, 3, 4, 5]
+    );
+    let first_led = &rig()[3].leds[0].name;
+    assert_eq!(resolve(&format!("led={first_led:?}"))[0].leds, [0]);
+}
+
+#[test]
+fn report_errors() {
+    let error = |s: &str| s.parse::<Selector>().unwrap_err();
+    assert_eq!(error("").kind, SelectorErrorKind::Empty);
+    assert_eq!(error("type=dram,").offset, 10);
+    assert_eq!(
+        error("type=dram colour=red").kind,
+        SelectorErrorKind::UnknownKey("colour".into())
+    );
+    assert_eq!(error("type=dram colour=red").offset, 10);
+    assert_eq!(error("name").kind, SelectorErrorKind::ExpectedOperator);
+    assert_eq!(error("name=").kind, SelectorErrorKind::ExpectedValue);
+    assert_eq!(
+        error("name=\"abc").kind,
+        SelectorErrorKind::UnterminatedString
+    );
+    assert_eq!(error("name=\"abc").offset, 5);
+    assert_eq!(
+        error("type=fridge").kind,
+        SelectorErrorKind::UnknownType("fridge".into())
+    );
+    assert_eq!(
+        error("type=fridge").to_string(),
+        "unknown controller type \"fridge\" at offset 5"
+    );
+}
@@ -0,0 +1,120 @@
+use orgb::{
+    mock_controller, Connection, ControllerData, ControllerType, Mode, Request, Rgb, Server,
+    VirtualDevice, PROTOCOL_VERSION,
+};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A device whose state can be inspected after it has been moved into the server.
+#[derive(Clone)]
+struct Widget {
+    data: Arc<Mutex<ControllerData>>,
+    modes: Arc<Mutex<Vec<u32>>>,
+}
+
+impl Widget {
+    fn new(num_leds: usize) -> Widget {
+        let mut data = mock_controller(ControllerType::LedStrip, "Widget", num_leds);
+        // Split the LEDs into two zones
+        let mut second_zone = data.zones[0].clone();
+        second_zone.name = "Second zone".into();
+        data.zones[0].leds_count = num_leds as u32 / 2;
+        second_zone.leds_count = num_leds as u32 - data.zones[0].leds_count;
+        data.zones.push(second_zone);
+        Widget {
+            data: Arc::new(Mutex::new(data)),
+            modes: Arc::default(),
+        }
+    }
+
+    fn colors(&self) -> Vec<Rgb> {
+        self.data.lock().unwrap().colors.clone()
+    }
+}
+
+impl VirtualDevice for Widget {
+    fn controller_data(&self) -> ControllerData {
+        self.data.lock().unwrap().clone()
+    }
+
+    fn update_leds(&mut self, colors: &[Rgb]) {
+        self.data.lock().unwrap().colors = colors.to_vec();
+    }
+
+    fn update_mode(&mut self, mode_idx: u32, _mode: &Mode) {
+        self.modes.lock().unwrap().push(mode_idx);
+    }
+}
+
+/// Poll a condition until it becomes true, or panic after the timeout.
+fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn serve_virtual_devices() {
+    let server = Server::start("127.0.0.1:0").unwrap();
+    let widget = Widget::new(4);
+    assert_eq!(server.add_device(widget.clone()), 0);
+
+    let mut serv = Connection::start(server.addr());
+    serv.set_client_name("Test client").unwrap();
+    assert_eq!(serv.negotiate_protocol_version().unwrap(), PROTOCOL_VERSION);
+    assert_eq!(
+        serv.list_controllers().unwrap(),
+        vec![widget.controller_data()]
+    );
+
+    serv.update_leds(0, &[Rgb(1, 1, 1); 4]).unwrap();
+    wait_until(|| widget.colors() == [Rgb(1, 1, 1); 4]);
+
+    serv.send(Request::UpdateZoneLeds {
+        controller_idx: 0,
+        zone_idx: 1,
+        colors: vec![Rgb(2, 2, 2); 2].into(),
+    })
+    .unwrap();
+    wait_until(|| widget.colors()[2..] == [Rgb(2, 2, 2); 2]);
+    assert_eq!(widget.colors()[..2], [Rgb(1, 1, 1); 2]);
+
+    serv.send(Request::UpdateSingleLed {
+        controller_idx: 0,
+        led_idx: 0,
+        color: Rgb(3, 3, 3),
+    })
+    .unwrap();
+    wait_until(|| widget.colors()[0] == Rgb(3, 3, 3));
+
+    let mode = widget.controller_data().modes[0].clone();
+    serv.send(Request::SaveMode {
+        controller_idx: 0,
+        mode_idx: 0,
+        mode: (&mode).into(),
+    })
+    .unwrap();
+    wait_until(|| *widget.modes.lock().unwrap() == [0]);
+}
+
+#[test]
+fn add_and_remove_devices() {
+    let server = Server::start("127.0.0.1:0").unwrap();
+    let mut serv = Connection::start(server.addr());
+    assert!(serv.devices_updated_reset());
+    assert_eq!(serv.controller_count().unwrap(), 0);
+
+    server.add_device(Widget::new(2));
+    wait_until(|| serv.devices_updated_reset());
+    assert_eq!(serv.controller_count().unwrap(), 1);
+
+    assert!(server.remove_device(0).is_some());
+    assert!(server.remove_device(0).is_none());
+    wait_until(|| serv.devices_updated_reset());
+    assert_eq!(serv.controller_count().unwrap(), 0);
+}
@@ -1,12 +1,12 @@
-//! Receive notifications when your screen is turned on and off by windows
+//! Receive notifications when your screen is turned on and off by windows, or when the session is locked
 
-use std::{ffi::CString, sync::mpsc, thread};
+use std::{ffi::CString, sync::mpsc, thread, time::Duration};
 use windows::{
     core::PCSTR,
     Win32::{
         Foundation::{BOOL, HANDLE, HWND, LPARAM, LRESULT, WPARAM},
-        System::{LibraryLoader, Power, SystemServices},
-        UI::WindowsAndMessaging,
+        System::{LibraryLoader, Power, RemoteDesktop, SystemInformation, SystemServices},
+        UI::{Input::KeyboardAndMouse, WindowsAndMessaging},
     },
 };
 
@@ -15,28 +15,40 @@ pub enum Event {
     Off,
     On,
     Dimmed,
+    Lock,
+    Unlock,
 }
 
 /// Window procedure, called upon DispatchMessageA
 unsafe extern "system" fn wndproc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    let tx =
+        WindowsAndMessaging::GetWindowLongPtrA(hwnd, WindowsAndMessaging::WINDOW_LONG_PTR_INDEX(0))
+            as *const mpsc::Sender<Event>;
     match msg {
         WindowsAndMessaging::WM_POWERBROADCAST => {
             if wparam.0 as u32 == WindowsAndMessaging::PBT_POWERSETTINGCHANGE {
                 let msgdata = &*(lparam.0 as *const Power::POWERBROADCAST_SETTING);
 
-                let tx = WindowsAndMessaging::GetWindowLongPtrA(
-                    hwnd,
-                    WindowsAndMessaging::WINDOW_LONG_PTR_INDEX(0),
-                ) as *const mpsc::Sender<Event>;
-
-                match (msgdata.PowerSetting, msgdata.Data) {
+                let event = match (msgdata.PowerSetting, msgdata.Data) {
                     (SystemServices::GUID_CONSOLE_DISPLAY_STATE, [0]) => Some(Event::Off),
                     (SystemServices::GUID_CONSOLE_DISPLAY_STATE, [1]) => Some(Event::On),
                     (SystemServices::GUID_CONSOLE_DISPLAY_STATE, [2]) => Some(Event::Dimmed),
                     (SystemServices::GUID_CONSOLE_DISPLAY_STATE, _) => unreachable!(),
                     _ => None,
+                };
+                if let Some(event) = event {
+                    (*tx).send(event).expect("Receiver has been destroyed");
                 }
-                .map(|event| (&*tx).send(event).expect("Receiver has been destroyed"));
+            }
+        }
+        WindowsAndMessaging::WM_WTSSESSION_CHANGE => {
+            let event = match wparam.0 as u32 {
+                WindowsAndMessaging::WTS_SESSION_LOCK => Some(Event::Lock),
+                WindowsAndMessaging::WTS_SESSION_UNLOCK => Some(Event::Unlock),
+                _ => None,
+            };
+            if let Some(event) = event {
+                (*tx).send(event).expect("Receiver has been destroyed");
             }
         }
         _ => return WindowsAndMessaging::DefWindowProcA(hwnd, msg, wparam, lparam),
@@ -44,7 +56,7 @@ unsafe extern "system" fn wndproc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam:
     LRESULT(0)
 }
 
-/// Start a thread that listens to display on/off events.
+/// Start a thread that listens to display on/off events and to session lock/unlock events.
 pub fn start() -> mpsc::Receiver<Event> {
     // Create a channel for the messages to be passed through
     let (tx, rx) = mpsc::channel();
@@ -55,12 +67,14 @@ pub fn start() -> mpsc::Receiver<Event> {
 
         // Register a window class
         let classname = CString::new("DisplayStatusClass").unwrap();
-        let mut windowclass = WindowsAndMessaging::WNDCLASSEXA::default();
-        windowclass.cbSize = std::mem::size_of::<WindowsAndMessaging::WNDCLASSEXA>() as u32;
-        windowclass.cbWndExtra = std::mem::size_of::<&mpsc::Sender<Event>>() as i32;
-        windowclass.lpfnWndProc = Some(wndproc);
-        windowclass.hInstance = hinstance.into();
-        windowclass.lpszClassName = PCSTR(classname.as_ptr() as *const u8);
+        let windowclass = WindowsAndMessaging::WNDCLASSEXA {
+            cbSize: std::mem::size_of::<WindowsAndMessaging::WNDCLASSEXA>() as u32,
+            cbWndExtra: std::mem::size_of::<&mpsc::Sender<Event>>() as i32,
+            lpfnWndProc: Some(wndproc),
+            hInstance: hinstance.into(),
+            lpszClassName: PCSTR(classname.as_ptr() as *const u8),
+            ..Default::default()
+        };
         unsafe {
             WindowsAndMessaging::RegisterClassExA(&windowclass);
         }
@@ -112,6 +126,15 @@ pub fn start() -> mpsc::Receiver<Event> {
         }
         .expect("Could not register to power setting events");
 
+        // Register to the notifications related to the session
+        unsafe {
+            RemoteDesktop::WTSRegisterSessionNotification(
+                hwnd,
+                RemoteDesktop::NOTIFY_FOR_THIS_SESSION,
+            )
+        }
+        .expect("Could not register to session events");
+
         // Run the event loop
         loop {
             let mut message = WindowsAndMessaging::MSG::default();
@@ -127,3 +150,23 @@ pub fn start() -> mpsc::Receiver<Event> {
 
     rx
 }
+
+/// Time since the last keyboard or mouse input of the user.
+pub fn idle_time() -> Duration {
+    let mut info = KeyboardAndMouse::LASTINPUTINFO {
+        cbSize: std::mem::size_of::<KeyboardAndMouse::LASTINPUTINFO>() as u32,
+        dwTime: 0,
+    };
+    if !unsafe { KeyboardAndMouse::GetLastInputInfo(&mut info) }.as_bool() {
+        return Duration::ZERO;
+    }
+    // Both are in milliseconds since the system started, which wraps around after 49 days
+    let now = unsafe { SystemInformation::GetTickCount() };
+    Duration::from_millis(now.wrapping_sub(info.dwTime) as u64)
+}
+
+/// The local time of day, as hours and minutes.
+pub fn local_time() -> (u32, u32) {
+    let time = unsafe { SystemInformation::GetLocalTime() };
+    (time.wHour as u32, time.wMinute as u32)
+}